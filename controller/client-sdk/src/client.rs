@@ -0,0 +1,684 @@
+use crate::codec::{Codec, CodecError, MessagePack};
+use crate::frame::{read_frame, write_frame, ReadFrameError};
+use crate::protocol::{Kind, Packet, Redacted, ServerLink, FEATURES, PROTOCOL_VERSION};
+use futures_core::Stream;
+use hmac::{Hmac, Mac};
+use log::{error, info, trace, warn};
+use sha2::Sha256;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, BufWriter};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// How long the client waits before reconnecting the first time the connection is
+/// lost. Every failed attempt after that doubles it, up to the maximum backoff.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How many packets may wait to be sent to the controller.
+const OUTBOUND_QUEUE_SIZE: usize = 256;
+
+/// How many events may wait to be received. Once it's full, the client stops reading
+/// from the controller, so events must be received for the client to stay connected.
+const EVENT_QUEUE_SIZE: usize = 256;
+
+/// How a [`ControllerClient`] connects and authenticates to the controller.
+#[derive(Clone)]
+pub struct ClientOptions {
+    /// The address of the controller, like `controller:25550`.
+    pub controller: String,
+    /// The name of the server, which must be unique.
+    pub name: String,
+    pub kind: Kind,
+    /// The address, including the port, that proxies can reach the server at.
+    pub ip: String,
+    /// The secret shared with the controller, if it has one.
+    pub secret: Option<String>,
+    /// The spawn token the controller handed to the server, if it spawned it.
+    /// Tokens can only be used once, so the controller rejects a server that
+    /// reconnects with one, see [`Event::Rejected`].
+    pub token: Option<String>,
+    /// Packets larger than this, in bytes, are compressed if the controller
+    /// supports compression.
+    pub compression_threshold: usize,
+    /// The largest frame, in bytes, that the controller may send.
+    pub max_frame_size: u32,
+    /// The longest the client waits before reconnecting.
+    pub max_backoff: Duration,
+    /// How to secure the connection, if the controller has TLS enabled.
+    pub tls: Option<TlsOptions>,
+}
+
+impl ClientOptions {
+    pub fn new(controller: String, name: String, kind: Kind, ip: String) -> Self {
+        Self {
+            controller,
+            name,
+            kind,
+            ip,
+            secret: None,
+            token: None,
+            compression_threshold: 1024,
+            max_frame_size: 1024 * 1024,
+            max_backoff: Duration::from_secs(30),
+            tls: None,
+        }
+    }
+}
+
+impl fmt::Debug for ClientOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientOptions")
+            .field("controller", &self.controller)
+            .field("name", &self.name)
+            .field("kind", &self.kind)
+            .field("ip", &self.ip)
+            .field("secret", &self.secret.as_ref().map(|_| ".."))
+            .field("token", &self.token.as_ref().map(|_| ".."))
+            .field("compression_threshold", &self.compression_threshold)
+            .field("max_frame_size", &self.max_frame_size)
+            .field("max_backoff", &self.max_backoff)
+            .field("tls", &self.tls)
+            .finish()
+    }
+}
+
+/// How a [`ControllerClient`] connects to a controller that has TLS enabled.
+#[derive(Clone)]
+pub struct TlsOptions {
+    pub connector: TlsConnector,
+    /// The name that the certificate of the controller must be valid for.
+    pub server_name: ServerName<'static>,
+}
+
+impl TlsOptions {
+    /// Trusts controllers with a certificate for `server_name` that is signed by
+    /// one of `roots`. A client certificate can be presented by building the
+    /// [`TlsConnector`] yourself instead.
+    pub fn new(
+        roots: RootCertStore,
+        server_name: ServerName<'static>,
+    ) -> Result<Self, tokio_rustls::rustls::Error> {
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+}
+
+impl fmt::Debug for TlsOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsOptions")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The connection to the controller, which may or may not be secured with TLS.
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+/// Something that happened on the connection to the controller.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// The client has (re)connected and authenticated, using `features`.
+    Connected {
+        features: Vec<String>,
+    },
+    /// The connection was lost, and the client is about to reconnect.
+    Disconnected {
+        reason: String,
+    },
+    /// The controller rejected the client, e.g. because it doesn't speak the same
+    /// protocol version or doesn't trust it. The client doesn't reconnect, and
+    /// this is the last event.
+    Rejected {
+        reason: String,
+    },
+    /// See [`Packet::Ping`]. Answer it with [`ControllerClient::pong`] to accept
+    /// players.
+    Ping {
+        timer: i32,
+    },
+    LinkServer(ServerLink),
+    UnlinkServer {
+        name: String,
+    },
+    SyncServers {
+        servers: Vec<ServerLink>,
+    },
+    TransportPlayer {
+        player: String,
+        to: String,
+    },
+    RequestQueued {
+        id: u32,
+    },
+    RequestFulfilled {
+        id: u32,
+        server: String,
+    },
+    RequestFailed {
+        id: u32,
+        reason: String,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("IO error: {_0}")]
+    IoError(#[from] std::io::Error),
+    #[error("ReadFrameError: {_0}")]
+    ReadFrameError(#[from] ReadFrameError),
+    #[error("Codec error: {_0}")]
+    CodecError(#[from] CodecError),
+    #[error("Controller rejected authentication: {_0}")]
+    Rejected(String),
+    #[error("Controller wants us to authenticate, but no secret is set")]
+    MissingSecret,
+    #[error("Received unexpected packet: {_0:?}")]
+    UnexpectedPacket(Box<Packet>),
+    #[error("Connection to the controller was lost")]
+    ConnectionLost,
+    #[error("Not connected to the controller")]
+    NotConnected,
+    #[error("Outbound queue is full")]
+    Overflow,
+}
+
+/// A connection to the controller, which is kept up in the background. Whenever
+/// it's lost, the client reconnects with exponential backoff, unless the controller
+/// rejected it. Heartbeats are answered without any help.
+///
+/// Dropping the client closes the connection.
+pub struct ControllerClient {
+    outbound: mpsc::Sender<Packet>,
+    connected: watch::Receiver<bool>,
+    next_request_id: AtomicU32,
+}
+
+impl ControllerClient {
+    /// Starts connecting to the controller, and returns the client along with the
+    /// events of the connection.
+    pub fn connect(options: ClientOptions) -> (Self, Events) {
+        let (outbound, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let (events, events_rx) = mpsc::channel(EVENT_QUEUE_SIZE);
+        let (connected_tx, connected) = watch::channel(false);
+
+        tokio::task::spawn(run(options, outbound_rx, events, connected_tx));
+
+        let client = Self {
+            outbound,
+            connected,
+            next_request_id: AtomicU32::new(0),
+        };
+
+        (
+            client,
+            Events {
+                receiver: events_rx,
+            },
+        )
+    }
+
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    /// Asks the controller for a server of `kind`, and to transport `player` to it
+    /// if set. Returns the ID of the request, which the replies to it are sent with.
+    pub fn request(&self, kind: Kind, player: Option<String>) -> Result<u32, ClientError> {
+        let id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+
+        self.send(Packet::Request {
+            kind,
+            player,
+            id: Some(id),
+        })?;
+        Ok(id)
+    }
+
+    pub fn update_active(&self, active: bool) -> Result<(), ClientError> {
+        self.send(Packet::UpdateActive { active })
+    }
+
+    pub fn pong(&self, timer: i32) -> Result<(), ClientError> {
+        self.send(Packet::Pong { timer })
+    }
+
+    /// Sends any packet to the controller. Packets can only be sent while the client
+    /// is connected, as they'd be stale by the time the client has reconnected.
+    pub fn send(&self, packet: Packet) -> Result<(), ClientError> {
+        if !self.is_connected() {
+            return Err(ClientError::NotConnected);
+        }
+
+        self.outbound.try_send(packet).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(_) => ClientError::Overflow,
+            mpsc::error::TrySendError::Closed(_) => ClientError::NotConnected,
+        })
+    }
+}
+
+/// The [`Event`]s of a [`ControllerClient`], which can be used as a [`Stream`].
+pub struct Events {
+    receiver: mpsc::Receiver<Event>,
+}
+
+impl Events {
+    /// Receives the next event, or `None` once the client has been dropped.
+    pub async fn recv(&mut self) -> Option<Event> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for Events {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Keeps the connection up until the client is dropped.
+async fn run(
+    options: ClientOptions,
+    mut outbound: mpsc::Receiver<Packet>,
+    events: mpsc::Sender<Event>,
+    connected: watch::Sender<bool>,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        info!("connecting to controller at {}", options.controller);
+        let result = session(&options, &mut outbound, &events, &connected).await;
+
+        // only back off for longer if we couldn't even authenticate
+        if connected.send_replace(false) {
+            backoff = INITIAL_BACKOFF;
+        }
+
+        let reason = match result {
+            Ok(()) => return,
+            // trying again won't change the controller's mind
+            Err(ClientError::Rejected(reason)) => {
+                error!("controller rejected authentication, not reconnecting: {reason}");
+                let _ = events.send(Event::Rejected { reason }).await;
+                return;
+            }
+            Err(err) => err.to_string(),
+        };
+
+        warn!("lost connection to controller: {reason}, reconnecting in {backoff:?}");
+        let _ = events.send(Event::Disconnected { reason }).await;
+
+        let sleep = tokio::time::sleep(backoff);
+        tokio::pin!(sleep);
+
+        loop {
+            tokio::select! {
+                () = &mut sleep => break,
+                // packets that were queued before the connection was lost are stale
+                packet = outbound.recv() => match packet {
+                    Some(packet) => trace!("dropping {packet:?} sent while disconnected"),
+                    None => return,
+                },
+            }
+        }
+
+        backoff = (backoff * 2).min(options.max_backoff);
+    }
+}
+
+/// Connects and authenticates to the controller, then passes packets along until
+/// the connection is lost. Returns `Ok` once the client has been dropped.
+async fn session(
+    options: &ClientOptions,
+    outbound: &mut mpsc::Receiver<Packet>,
+    events: &mpsc::Sender<Event>,
+    connected: &watch::Sender<bool>,
+) -> Result<(), ClientError> {
+    let stream = TcpStream::connect(&options.controller).await?;
+    let stream: Box<dyn Connection> = match &options.tls {
+        Some(tls) => {
+            let server_name = tls.server_name.clone();
+            Box::new(tls.connector.connect(server_name, stream).await?)
+        }
+        None => Box::new(stream),
+    };
+
+    let (read_half, write_half) = tokio::io::split(stream);
+    let mut writer = BufWriter::new(write_half);
+
+    // reading a frame can't be cancelled halfway, so it's done in a task of its own
+    let (inbound_tx, mut inbound) = mpsc::channel(1);
+    let reader = tokio::task::spawn(read_packets(read_half, options.max_frame_size, inbound_tx));
+
+    let result = async {
+        let authentication = Packet::Authentication {
+            name: options.name.clone(),
+            kind: options.kind.clone(),
+            ip: options.ip.clone(),
+            version: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(ToString::to_string).collect(),
+            token: options.token.clone().map(Redacted),
+        };
+        write_packet(&mut writer, &authentication, None).await?;
+
+        let features = loop {
+            match inbound.recv().await.ok_or(ClientError::ConnectionLost)?? {
+                Packet::Challenge { nonce } => {
+                    let secret = options.secret.as_ref().ok_or(ClientError::MissingSecret)?;
+                    let signature = Redacted(sign(secret, &nonce));
+                    let response = Packet::ChallengeResponse { signature };
+                    write_packet(&mut writer, &response, None).await?;
+                }
+                Packet::AuthenticationAccepted { features, .. } => break features,
+                Packet::AuthenticationRejected { reason, .. } => {
+                    return Err(ClientError::Rejected(reason));
+                }
+                packet => return Err(ClientError::UnexpectedPacket(Box::new(packet))),
+            }
+        };
+
+        info!("connected to controller with features {features:?}");
+        let compression = features
+            .iter()
+            .any(|feature| feature == "compression")
+            .then_some(options.compression_threshold);
+
+        connected.send_replace(true);
+        let _ = events.send(Event::Connected { features }).await;
+
+        loop {
+            tokio::select! {
+                packet = inbound.recv() => {
+                    let packet = packet.ok_or(ClientError::ConnectionLost)??;
+                    trace!("received packet {packet:?}");
+
+                    let event = match packet {
+                        Packet::Heartbeat { sent_at } => {
+                            let ack = Packet::HeartbeatAck { sent_at };
+                            write_packet(&mut writer, &ack, compression).await?;
+                            continue;
+                        }
+                        Packet::Ping { timer } => Event::Ping { timer },
+                        Packet::LinkServer {
+                            name,
+                            address,
+                            port,
+                            priority,
+                        } => Event::LinkServer(ServerLink {
+                            name,
+                            address,
+                            port,
+                            priority,
+                        }),
+                        Packet::UnlinkServer { name } => Event::UnlinkServer { name },
+                        Packet::SyncServers { servers } => Event::SyncServers { servers },
+                        Packet::TransportPlayer { player, to } => {
+                            Event::TransportPlayer { player, to }
+                        }
+                        Packet::RequestQueued { id } => Event::RequestQueued { id },
+                        Packet::RequestFulfilled { id, server } => {
+                            Event::RequestFulfilled { id, server }
+                        }
+                        Packet::RequestFailed { id, reason } => Event::RequestFailed { id, reason },
+                        packet => return Err(ClientError::UnexpectedPacket(Box::new(packet))),
+                    };
+
+                    // nobody may be listening for events, which is fine
+                    let _ = events.send(event).await;
+                }
+                packet = outbound.recv() => {
+                    let Some(packet) = packet else { return Ok(()) };
+                    trace!("sending packet {packet:?}");
+                    write_packet(&mut writer, &packet, compression).await?;
+                }
+            }
+        }
+    }
+    .await;
+
+    reader.abort();
+    result
+}
+
+/// Reads packets until the connection is lost or the session is over.
+async fn read_packets(
+    mut read_half: impl AsyncRead + Unpin,
+    max_frame_size: u32,
+    inbound: mpsc::Sender<Result<Packet, ClientError>>,
+) {
+    loop {
+        let packet = match read_frame(&mut read_half, max_frame_size).await {
+            Ok(frame) => MessagePack.decode(&frame).map_err(ClientError::from),
+            Err(err) => Err(err.into()),
+        };
+
+        let failed = packet.is_err();
+        if inbound.send(packet).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn write_packet<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    packet: &Packet,
+    compression: Option<usize>,
+) -> Result<(), ClientError> {
+    let bytes = MessagePack.encode(packet)?;
+    write_frame(writer, &bytes, compression).await?;
+    Ok(())
+}
+
+/// Signs the nonce of a challenge with the shared secret, the same way the
+/// controller checks it: HMAC-SHA256, encoded as hex.
+fn sign(secret: &str, nonce: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(nonce.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio::time::Instant;
+    use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+
+    async fn listen() -> (TcpListener, ClientOptions) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address: SocketAddr = listener.local_addr().unwrap();
+
+        let options = ClientOptions::new(
+            address.to_string(),
+            "lobby-0".to_owned(),
+            Kind::Lobby,
+            "/127.0.0.1:25565".to_owned(),
+        );
+
+        (listener, options)
+    }
+
+    async fn send<S: AsyncWrite + Unpin>(stream: &mut S, packet: &Packet) {
+        write_packet(stream, packet, None).await.unwrap();
+    }
+
+    async fn recv<S: AsyncRead + Unpin>(stream: &mut S) -> Packet {
+        let frame = read_frame(stream, 1024).await.unwrap();
+        MessagePack.decode(&frame).unwrap()
+    }
+
+    async fn accept<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) {
+        let packet = recv(stream).await;
+        assert!(matches!(packet, Packet::Authentication { .. }));
+
+        let accepted = Packet::AuthenticationAccepted {
+            version: PROTOCOL_VERSION,
+            features: Vec::new(),
+        };
+        send(stream, &accepted).await;
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_its_token() {
+        let (listener, mut options) = listen().await;
+        options.token = Some("token".to_owned());
+
+        let (client, mut events) = ControllerClient::connect(options);
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let Packet::Authentication {
+            name, kind, token, ..
+        } = recv(&mut stream).await
+        else {
+            panic!("expected an authentication packet");
+        };
+
+        assert_eq!(name, "lobby-0");
+        assert_eq!(kind, Kind::Lobby);
+        assert_eq!(token, Some(Redacted("token".to_owned())));
+
+        let accepted = Packet::AuthenticationAccepted {
+            version: PROTOCOL_VERSION,
+            features: Vec::new(),
+        };
+        send(&mut stream, &accepted).await;
+
+        let event = events.recv().await.unwrap();
+        assert!(matches!(event, Event::Connected { .. }));
+        assert!(client.is_connected());
+    }
+
+    #[tokio::test]
+    async fn answers_the_challenge() {
+        let (listener, mut options) = listen().await;
+        options.secret = Some("key".to_owned());
+
+        let (_client, _events) = ControllerClient::connect(options);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        recv(&mut stream).await;
+
+        let challenge = Packet::Challenge {
+            nonce: "The quick brown fox jumps over the lazy dog".to_owned(),
+        };
+        send(&mut stream, &challenge).await;
+
+        let Packet::ChallengeResponse { signature } = recv(&mut stream).await else {
+            panic!("expected a challenge response");
+        };
+
+        // HMAC-SHA256 test vector
+        let expected = "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8";
+        assert_eq!(signature.as_str(), expected);
+    }
+
+    #[tokio::test]
+    async fn answers_heartbeats() {
+        let (listener, options) = listen().await;
+
+        let (_client, _events) = ControllerClient::connect(options);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        accept(&mut stream).await;
+
+        send(&mut stream, &Packet::Heartbeat { sent_at: 42 }).await;
+
+        let ack = recv(&mut stream).await;
+        assert!(matches!(ack, Packet::HeartbeatAck { sent_at: 42 }));
+    }
+
+    #[tokio::test]
+    async fn stops_once_rejected() {
+        let (listener, options) = listen().await;
+
+        let (client, mut events) = ControllerClient::connect(options);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        recv(&mut stream).await;
+
+        let rejected = Packet::AuthenticationRejected {
+            version: PROTOCOL_VERSION,
+            reason: "no".to_owned(),
+        };
+        send(&mut stream, &rejected).await;
+
+        let event = events.recv().await.unwrap();
+        assert!(matches!(event, Event::Rejected { reason } if reason == "no"));
+        assert!(events.recv().await.is_none());
+        assert!(!client.is_connected());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backs_off_between_attempts() {
+        let (listener, mut options) = listen().await;
+        options.max_backoff = Duration::from_secs(4);
+
+        let (_client, _events) = ControllerClient::connect(options);
+
+        // every attempt fails before the client can authenticate
+        let mut attempts = Vec::new();
+        for _ in 0..5 {
+            let (stream, _) = listener.accept().await.unwrap();
+            attempts.push(Instant::now());
+            drop(stream);
+        }
+
+        let backoffs: Vec<u64> = attempts
+            .windows(2)
+            .map(|attempts| (attempts[1] - attempts[0]).as_secs())
+            .collect();
+
+        assert_eq!(backoffs, [1, 2, 4, 4]);
+    }
+
+    #[tokio::test]
+    async fn connects_over_tls() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+        let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], PrivateKeyDer::Pkcs8(key))
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+
+        let (listener, mut options) = listen().await;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        options.tls = Some(TlsOptions::new(roots, server_name).unwrap());
+
+        let (client, mut events) = ControllerClient::connect(options);
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = acceptor.accept(stream).await.unwrap();
+        accept(&mut stream).await;
+
+        let event = events.recv().await.unwrap();
+        assert!(matches!(event, Event::Connected { .. }));
+        assert!(client.is_connected());
+    }
+}
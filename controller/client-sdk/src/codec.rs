@@ -0,0 +1,62 @@
+use crate::protocol::Packet;
+use std::fmt;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Turns [`Packet`]s into the contents of a frame and back. Every connection uses a
+/// single codec for both directions, which the client picks with its first frame.
+pub trait Codec: fmt::Debug + Send + Sync {
+    fn encode(&self, packet: &Packet) -> Result<Vec<u8>, CodecError>;
+    fn decode(&self, bytes: &[u8]) -> Result<Packet, CodecError>;
+}
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("MessagePack encode error: {_0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[error("MessagePack decode error: {_0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+    #[error("JSON error: {_0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// MessagePack with structs encoded as maps, which is what the plugins speak.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn encode(&self, packet: &Packet) -> Result<Vec<u8>, CodecError> {
+        Ok(packet.to_bytes()?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Packet, CodecError> {
+        Ok(Packet::from_bytes(bytes)?)
+    }
+}
+
+/// Plain JSON, so that test clients and other tooling can be written without a
+/// MessagePack library. Packets look like `{"Ping": {"timer": 0}}`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode(&self, packet: &Packet) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(packet)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Packet, CodecError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Picks the codec of a connection from the first frame that the client sent. A
+/// packet encoded as JSON always starts with `{`, while a packet encoded as
+/// MessagePack starts with the marker of a map instead.
+pub fn detect(frame: &[u8]) -> Arc<dyn Codec> {
+    let first = frame.iter().find(|byte| !byte.is_ascii_whitespace());
+
+    match first {
+        Some(b'{') => Arc::new(Json),
+        _ => Arc::new(MessagePack),
+    }
+}
@@ -0,0 +1,219 @@
+use std::io::ErrorKind;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Every frame starts with a `u32` header, which holds the length of the frame in
+/// its lower 31 bits. The highest bit is set if the frame is compressed with zstd,
+/// which peers may only do once both sides agreed on the `compression` feature.
+pub const COMPRESSED_FLAG: u32 = 1 << 31;
+
+/// A frame that the peer sent which can't possibly contain a valid [`Packet`].
+///
+/// [`Packet`]: crate::protocol::Packet
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("connection closed after {read} bytes of the frame header")]
+    TruncatedHeader { read: usize },
+    #[error("frame of {length} bytes exceeds the maximum of {max} bytes")]
+    TooLarge { length: u32, max: u32 },
+    #[error("connection closed before the frame of {length} bytes was read")]
+    Truncated { length: u32 },
+    #[error("compressed frame of {length} bytes can't be decompressed: {source}")]
+    Decompression { length: u32, source: std::io::Error },
+}
+
+#[derive(Error, Debug)]
+pub enum ReadFrameError {
+    #[error("IO error: {_0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Malformed frame: {_0}")]
+    MalformedFrame(#[from] FrameError),
+}
+
+/// Reads the contents of the next frame, decompressed but not decoded yet. Frames
+/// larger than `max_frame_size`, before or after decompressing, are rejected.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_frame_size: u32,
+) -> Result<Vec<u8>, ReadFrameError> {
+    let header = read_header(reader).await?;
+    let compressed = header & COMPRESSED_FLAG != 0;
+    let length = header & !COMPRESSED_FLAG;
+
+    // check the length before allocating, as anyone can connect to us
+    let max = max_frame_size;
+    if length > max {
+        return Err(FrameError::TooLarge { length, max }.into());
+    }
+
+    let mut buffer = vec![0; length as usize];
+    match reader.read_exact(&mut buffer).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            return Err(FrameError::Truncated { length }.into());
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    // a compressed frame may not be any larger than the frame limit either
+    if compressed {
+        buffer = zstd::bulk::decompress(&buffer, max as usize)
+            .map_err(|source| FrameError::Decompression { length, source })?;
+    }
+
+    Ok(buffer)
+}
+
+/// Reads the header of a frame. A connection that is closed between two frames is
+/// an IO error, while one that is closed in the middle of a header is malformed.
+async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> Result<u32, ReadFrameError> {
+    let mut header = [0; 4];
+    let mut read = 0;
+
+    while read < header.len() {
+        match reader.read(&mut header[read..]).await? {
+            0 if read == 0 => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+            0 => return Err(FrameError::TruncatedHeader { read }.into()),
+            n => read += n,
+        }
+    }
+
+    Ok(u32::from_be_bytes(header))
+}
+
+/// Writes a single frame, compressing it if compression is enabled and the frame is
+/// larger than the threshold. Frames that don't get any smaller are sent as is.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    bytes: &[u8],
+    compression: Option<usize>,
+) -> Result<(), std::io::Error> {
+    let compressed = match compression {
+        Some(threshold) if bytes.len() > threshold => Some(zstd::bulk::compress(
+            bytes,
+            zstd::DEFAULT_COMPRESSION_LEVEL,
+        )?)
+        .filter(|compressed| compressed.len() < bytes.len()),
+        _ => None,
+    };
+
+    match &compressed {
+        Some(compressed) => {
+            writer
+                .write_u32(compressed.len() as u32 | COMPRESSED_FLAG)
+                .await?;
+            writer.write_all(compressed).await?;
+        }
+        None => {
+            writer.write_u32(bytes.len() as u32).await?;
+            writer.write_all(bytes).await?;
+        }
+    }
+
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: u32 = 1024;
+
+    async fn round_trip(bytes: &[u8], compression: Option<usize>) -> (Vec<u8>, u32) {
+        let mut written = Vec::new();
+        write_frame(&mut written, bytes, compression).await.unwrap();
+
+        let header = u32::from_be_bytes(written[..4].try_into().unwrap());
+        let frame = read_frame(&mut written.as_slice(), MAX).await.unwrap();
+        (frame, header)
+    }
+
+    #[tokio::test]
+    async fn round_trips_uncompressed_frames() {
+        let bytes = vec![7; 512];
+
+        let (frame, header) = round_trip(&bytes, None).await;
+        assert_eq!(frame, bytes);
+        assert_eq!(header, 512);
+    }
+
+    #[tokio::test]
+    async fn round_trips_compressed_frames() {
+        let bytes = vec![7; 512];
+
+        let (frame, header) = round_trip(&bytes, Some(256)).await;
+        assert_eq!(frame, bytes);
+        assert_ne!(header & COMPRESSED_FLAG, 0);
+        assert!(header & !COMPRESSED_FLAG < 512);
+    }
+
+    #[tokio::test]
+    async fn doesnt_compress_frames_below_the_threshold() {
+        let bytes = vec![7; 128];
+
+        let (frame, header) = round_trip(&bytes, Some(256)).await;
+        assert_eq!(frame, bytes);
+        assert_eq!(header, 128);
+    }
+
+    #[tokio::test]
+    async fn rejects_large_frames_before_reading_them() {
+        // only the header, so the frame can't have been read
+        let header = (MAX + 1).to_be_bytes();
+
+        let result = read_frame(&mut header.as_slice(), MAX).await;
+        assert!(matches!(
+            result,
+            Err(ReadFrameError::MalformedFrame(FrameError::TooLarge { length, max: MAX }))
+                if length == MAX + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_frames_that_decompress_beyond_the_maximum() {
+        let mut written = Vec::new();
+        write_frame(&mut written, &vec![7; 4096], Some(0))
+            .await
+            .unwrap();
+
+        let result = read_frame(&mut written.as_slice(), MAX).await;
+        assert!(matches!(
+            result,
+            Err(ReadFrameError::MalformedFrame(
+                FrameError::Decompression { .. }
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_headers() {
+        let header = 16u32.to_be_bytes();
+
+        let result = read_frame(&mut &header[..3], MAX).await;
+        assert!(matches!(
+            result,
+            Err(ReadFrameError::MalformedFrame(
+                FrameError::TruncatedHeader { read: 3 }
+            ))
+        ));
+
+        // closing the connection between two frames is fine
+        let result = read_frame(&mut &header[..0], MAX).await;
+        assert!(matches!(result, Err(ReadFrameError::IoError(_))));
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_frames() {
+        let mut written = Vec::new();
+        write_frame(&mut written, &[7; 16], None).await.unwrap();
+        written.truncate(10);
+
+        let result = read_frame(&mut written.as_slice(), MAX).await;
+        assert!(matches!(
+            result,
+            Err(ReadFrameError::MalformedFrame(FrameError::Truncated {
+                length: 16
+            }))
+        ));
+    }
+}
@@ -0,0 +1,19 @@
+/// The protocol module contains the [`protocol::Packet`]s that the controller and
+/// its clients exchange, along with the version and features of the protocol.
+pub mod protocol;
+
+/// The frame module reads and writes the frames that every packet is sent in,
+/// compressing them if needed.
+pub mod frame;
+
+/// The codec module contains the ways packets can be encoded in a frame. Clients
+/// speak MessagePack, but may use JSON instead to make debugging easier.
+pub mod codec;
+
+/// The client module connects to the controller as a server, and keeps the
+/// connection up.
+mod client;
+pub use client::{ClientError, ClientOptions, ControllerClient, Event, Events, TlsOptions};
+
+/// The TLS library that [`TlsOptions`] are built with.
+pub use tokio_rustls;
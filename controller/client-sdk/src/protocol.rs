@@ -0,0 +1,387 @@
+use derive_more::Display;
+use rmp_serde::{Deserializer, Serializer};
+#[cfg(feature = "schema")]
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The version of the protocol spoken by the controller. It must be bumped whenever
+/// a change is made to [`Packet`] that older clients wouldn't understand, such as
+/// adding a new packet that is sent to clients.
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// The oldest version of the protocol that the controller still accepts clients for.
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+/// Optional parts of the protocol that the controller supports. Clients state the
+/// features they support in the [`Authentication`] packet, and only the features
+/// that both sides support are used for the connection.
+///
+/// - `sync-servers`: the proxy wants a [`SyncServers`] packet after authenticating.
+/// - `compression`: large frames may be compressed with zstd, see [`COMPRESSED_FLAG`].
+///
+/// [`COMPRESSED_FLAG`]: crate::frame::COMPRESSED_FLAG
+///
+/// [`Authentication`]: Packet::Authentication
+/// [`SyncServers`]: Packet::SyncServers
+pub const FEATURES: &[&str] = &["sync-servers", "compression"];
+
+/// A connection between a given server and the controller will **only** communicate
+/// in [`Packet`]s. Some packets are not expected to always be able to be sent in
+/// specific states, and would be unacceptable to do so.
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub enum Packet {
+    /// The [`Authentication`] packet is sent from the client to the controller to
+    /// establish the connection. It contains identifying information about the server,
+    /// so that the controller can appropriately handle the messages it may send.
+    ///
+    /// [`Authentication`]: Packet::Authentication
+    Authentication {
+        name: String,
+        kind: Kind,
+        ip: String,
+        /// The [`PROTOCOL_VERSION`] that the client speaks. Clients from before
+        /// the protocol was versioned don't send it, which is read as version 0.
+        #[serde(default)]
+        version: u32,
+        /// The [`FEATURES`] that the client supports.
+        #[serde(default)]
+        features: Vec<String>,
+        /// The one-time token that the controller handed to the server when it
+        /// was spawned, proving that the server is the one spawned under `name`.
+        /// Only proxies, which aren't spawned by the controller, don't have one.
+        #[serde(default)]
+        token: Option<Redacted>,
+    },
+    /// The [`Challenge`] packet is sent from the controller to a client in response
+    /// to an [`Authentication`] packet, if the controller has a shared secret. The
+    /// client must prove that it knows the secret by answering with a
+    /// [`ChallengeResponse`] packet, otherwise it is disconnected.
+    ///
+    /// [`Authentication`]: Packet::Authentication
+    /// [`Challenge`]: Packet::Challenge
+    /// [`ChallengeResponse`]: Packet::ChallengeResponse
+    Challenge {
+        /// A random value, encoded as hex. It's different for every connection,
+        /// so that a response can't be replayed.
+        nonce: String,
+    },
+    /// The [`ChallengeResponse`] packet is sent from a client to the controller in
+    /// response to a [`Challenge`] packet.
+    ///
+    /// [`Challenge`]: Packet::Challenge
+    /// [`ChallengeResponse`]: Packet::ChallengeResponse
+    ChallengeResponse {
+        /// The HMAC-SHA256 of the nonce, keyed with the shared secret and encoded
+        /// as hex.
+        signature: Redacted,
+    },
+    /// The [`AuthenticationAccepted`] packet is sent from the controller to a client
+    /// once it has authenticated with a supported protocol version.
+    /// It contains the protocol version the connection uses, and the features that
+    /// both the client and the controller support.
+    ///
+    /// [`Authentication`]: Packet::Authentication
+    /// [`AuthenticationAccepted`]: Packet::AuthenticationAccepted
+    AuthenticationAccepted { version: u32, features: Vec<String> },
+    /// The [`AuthenticationRejected`] packet is sent from the controller to a client
+    /// in response to an [`Authentication`] packet with a protocol version the
+    /// controller doesn't support, or when it failed the [`Challenge`]. The
+    /// controller closes the connection right after.
+    ///
+    /// [`Authentication`]: Packet::Authentication
+    /// [`AuthenticationRejected`]: Packet::AuthenticationRejected
+    /// [`Challenge`]: Packet::Challenge
+    AuthenticationRejected { version: u32, reason: String },
+    /// The [`Request`] packet is sent from the client to the controller when the
+    /// client wants to make the controller aware of a request that a player wants
+    /// to join a specific kind of server. In the event that no player is specified,
+    /// this is a request to ensure a server of the specified kind is established.
+    ///
+    /// [`Request`]: Packet::Request
+    Request {
+        kind: Kind,
+        /// The UUID of the player that wants to connect to the desired server,
+        /// if applicable.
+        player: Option<String>,
+        /// An ID that the client picks to tell the replies to its requests apart.
+        /// If it's set, the controller replies with [`RequestQueued`],
+        /// [`RequestFulfilled`] and [`RequestFailed`] packets.
+        ///
+        /// [`RequestQueued`]: Packet::RequestQueued
+        /// [`RequestFulfilled`]: Packet::RequestFulfilled
+        /// [`RequestFailed`]: Packet::RequestFailed
+        #[serde(default)]
+        id: Option<u32>,
+    },
+    /// The [`RequestQueued`] packet is sent from the controller to a client when its
+    /// [`Request`] can't be fulfilled right away, for example because a server has
+    /// to be started first. A [`RequestFulfilled`] or [`RequestFailed`] packet
+    /// follows once it's known what happened to the request.
+    ///
+    /// [`Request`]: Packet::Request
+    /// [`RequestQueued`]: Packet::RequestQueued
+    /// [`RequestFulfilled`]: Packet::RequestFulfilled
+    /// [`RequestFailed`]: Packet::RequestFailed
+    RequestQueued { id: u32 },
+    /// The [`RequestFulfilled`] packet is sent from the controller to a client once
+    /// its [`Request`] has been fulfilled. If the request was for a player, the
+    /// player is being transported to `server`.
+    ///
+    /// [`Request`]: Packet::Request
+    /// [`RequestFulfilled`]: Packet::RequestFulfilled
+    RequestFulfilled { id: u32, server: String },
+    /// The [`RequestFailed`] packet is sent from the controller to a client when its
+    /// [`Request`] can't be fulfilled.
+    ///
+    /// [`Request`]: Packet::Request
+    /// [`RequestFailed`]: Packet::RequestFailed
+    RequestFailed { id: u32, reason: String },
+    /// The [`LinkServer`] packet is sent from the controller to every client that
+    /// is designated as a proxy server. This is sent to the proxy servers upon
+    /// a connection so that the proxy servers can dynamically add new servers for
+    /// players to connect to.
+    ///
+    /// [`LinkServer`]: Packet::LinkServer
+    LinkServer {
+        name: String,
+        address: String,
+        port: u16,
+        /// The priority is used to define the order in which linked server takes
+        /// precedence over others. The server with the highest priority has all
+        /// players forwarded to it on join.
+        ///
+        /// # Examples
+        ///
+        /// For every numeric list item, we will state the priority and server name,
+        /// and then state the server chosen that the proxy will forward players to.
+        ///
+        /// 1. `1 - "limbo"`, **Server:** `"limbo"`
+        ///
+        ///    The only server connected is `"limbo"` with a priority of 1, so all
+        ///    players will be forwarded to it.
+        ///
+        /// 2. `2 - "lobby"`, **Server:** `"lobby"`
+        ///
+        ///    Now there are two servers: `"limbo"` with priority 1 and `"lobby"`
+        ///    with priority 2. Because `"lobby"` has the higher priority, the proxy
+        ///    will connect players to it.
+        ///
+        /// 3. `0 - "minigame-0"`, **Server:** `"lobby"`
+        ///
+        ///    Because `"lobby"` still has the highest priority (2) than any other
+        ///    server (`"limbo"`: 1, `"minigame-0"`: 0), the proxy will still forward
+        ///    all players to that server.
+        priority: u16,
+    },
+    /// The [`UnlinkServer`] packet is sent from the controller to every client that
+    /// is designated as a proxy server when a connection to the controller is
+    /// terminated. If the controller is unable to reach a server, it is safe to
+    /// assume that the unreachable server is dead. Therefore, we don't want players
+    /// routed to that server in any circumstance, so we inform the proxy to unlink
+    /// the connection to that server.
+    ///
+    /// [`UnlinkServer`]: Packet::UnlinkServer
+    UnlinkServer { name: String },
+    /// The [`SyncServers`] packet is sent from the controller to a proxy server
+    /// right after it has authenticated. It contains every server that is currently
+    /// linked, so that a proxy which has just (re)connected knows about every server
+    /// the controller knows about. The proxy should unlink any server it knows
+    /// about that isn't in the list.
+    ///
+    /// [`SyncServers`]: Packet::SyncServers
+    SyncServers { servers: Vec<ServerLink> },
+    /// The [`TransportPlayer`] packet is sent from the controller to the proxy server
+    /// that the player is connected to when a player is to be transported to
+    /// another server. There may be any number of reasons behind the transport, but
+    /// the most likely reason is that a [`Request`] packet was able to be fulfilled.
+    ///
+    /// If the controller does not know which proxy the player is connected to (see
+    /// [`PlayerJoined`]), the packet is sent to every proxy server.
+    ///
+    /// [`TransportPlayer`]: Packet::TransportPlayer
+    /// [`Request`]: Packet::Request
+    /// [`PlayerJoined`]: Packet::PlayerJoined
+    TransportPlayer { player: String, to: String },
+    /// The [`Ping`] packet is sent from the controller (specifically, a minigame
+    /// cluster) to a minigame client to ask it if it is accepting players. The
+    /// first minigame server to respond with a [`Pong`] packet will have a player
+    /// transported to it to participate in the minigame. Servers that do not want
+    /// to accept players should not respond to the [`Ping`] packet.
+    ///
+    /// [`Ping`]: Packet::Ping
+    /// [`Pong`]: Packet::Pong
+    Ping { timer: i32 },
+    /// The [`Pong`] packet is sent from a minigame server to the controller (specifically,
+    /// a minigame cluster) only after a [`Ping`] packet has been sent. Sending a
+    /// [`Pong`] packet indicates that a server is willing to accept more players,
+    /// but servers that do not want players should simply not respond to the [`Ping`]
+    /// packet.
+    ///
+    /// [`Ping`]: Packet::Ping
+    /// [`Pong`]: Packet::Pong
+    Pong { timer: i32 },
+    /// The [`UpdateActive`] packet is sent from a minigame server to the controller
+    /// (specifically, a minigame cluster) when the minigame server wants to change
+    /// whether or not it's "active". An inactive minigame server will not receive
+    /// [`Ping`]s, whereas an active minigame server will receive [`Ping`]s.
+    ///
+    /// [`Ping`]: Packet::Ping
+    /// [`UpdateActive`]: Packet::UpdateActive
+    UpdateActive { active: bool },
+    /// The [`PlayerJoined`] packet is sent from a proxy server to the controller
+    /// when a player connects to that proxy. Because multiple proxies may be
+    /// connected at once, the controller uses this to know which proxy it must
+    /// send a [`TransportPlayer`] packet to in order to move the player.
+    ///
+    /// [`PlayerJoined`]: Packet::PlayerJoined
+    /// [`TransportPlayer`]: Packet::TransportPlayer
+    PlayerJoined { player: String },
+    /// The [`PlayerLeft`] packet is sent from a proxy server to the controller
+    /// when a player disconnects from that proxy. It is the counterpart to the
+    /// [`PlayerJoined`] packet.
+    ///
+    /// [`PlayerJoined`]: Packet::PlayerJoined
+    /// [`PlayerLeft`]: Packet::PlayerLeft
+    PlayerLeft { player: String },
+    /// The [`Heartbeat`] packet is sent from the controller to every client that has
+    /// authenticated, at a regular interval. The client must answer it with a
+    /// [`HeartbeatAck`] packet. A client that doesn't send anything for too long
+    /// is considered dead, and is unlinked.
+    ///
+    /// [`Heartbeat`]: Packet::Heartbeat
+    /// [`HeartbeatAck`]: Packet::HeartbeatAck
+    Heartbeat {
+        /// When the heartbeat was sent, in milliseconds since the connection was
+        /// established. Only the controller makes sense of it.
+        sent_at: u64,
+    },
+    /// The [`HeartbeatAck`] packet is sent from a client to the controller in
+    /// response to a [`Heartbeat`] packet, with the same `sent_at`. The controller
+    /// uses it to measure the round-trip latency to the client.
+    ///
+    /// [`Heartbeat`]: Packet::Heartbeat
+    /// [`HeartbeatAck`]: Packet::HeartbeatAck
+    HeartbeatAck { sent_at: u64 },
+    /// The [`PlayerCount`] packet is sent from a lobby or minigame server to the
+    /// controller whenever the amount of players on it changes, and right after it
+    /// has authenticated. The controller uses it to decide which lobby server players
+    /// are placed into, and to stop minigame servers that have been empty for too long.
+    ///
+    /// [`PlayerCount`]: Packet::PlayerCount
+    PlayerCount { players: u32 },
+}
+
+impl Packet {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
+        Packet::deserialize(&mut Deserializer::new(bytes))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        let mut buffer = Vec::new();
+
+        self.serialize(&mut Serializer::new(&mut buffer).with_struct_map())
+            .map(|_| buffer)
+    }
+}
+
+/// A string that proves who a client is, such as a spawn token. It's sent as a plain
+/// string, but never shows up when a packet is logged.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(transparent)]
+pub struct Redacted(pub String);
+
+impl Redacted {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Redacted(..)")
+    }
+}
+
+/// A server that proxies can forward players to. The fields are the same as those
+/// of the [`LinkServer`] packet.
+///
+/// [`LinkServer`]: Packet::LinkServer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+pub struct ServerLink {
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    pub priority: u16,
+}
+
+impl From<ServerLink> for Packet {
+    fn from(server: ServerLink) -> Self {
+        let ServerLink {
+            name,
+            address,
+            port,
+            priority,
+        } = server;
+
+        Packet::LinkServer {
+            name,
+            address,
+            port,
+            priority,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Display)]
+#[cfg_attr(feature = "schema", derive(JsonSchema))]
+#[serde(tag = "tag", content = "payload")]
+pub enum Kind {
+    #[display(fmt = "limbo")]
+    Limbo,
+    #[display(fmt = "proxy")]
+    Proxy,
+    #[display(fmt = "lobby")]
+    Lobby,
+    #[display(fmt = "minigame-{kind}")]
+    Minigame { kind: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_packets_without_their_secrets() {
+        let authentication = Packet::Authentication {
+            name: "lobby-0".to_owned(),
+            kind: Kind::Lobby,
+            ip: "/127.0.0.1:25565".to_owned(),
+            version: PROTOCOL_VERSION,
+            features: Vec::new(),
+            token: Some(Redacted("spawn-token".to_owned())),
+        };
+
+        let response = Packet::ChallengeResponse {
+            signature: Redacted("signature".to_owned()),
+        };
+
+        let logged = format!("{authentication:?} {response:?}");
+        assert!(logged.contains("lobby-0"));
+        assert!(!logged.contains("spawn-token"));
+        assert!(!logged.contains("signature\""));
+    }
+
+    #[test]
+    fn sends_secrets_as_plain_strings() {
+        let response = Packet::ChallengeResponse {
+            signature: Redacted("signature".to_owned()),
+        };
+
+        let json = serde_json::to_string(&response).unwrap();
+        assert_eq!(json, r#"{"ChallengeResponse":{"signature":"signature"}}"#);
+    }
+}
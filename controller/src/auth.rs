@@ -0,0 +1,43 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The secret shared between the controller and every server. It's never printed,
+/// so that it doesn't end up in the logs.
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// Creates a random nonce for a client to sign, encoded as hex.
+pub fn new_nonce() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Creates a random, unguessable token, encoded as hex.
+pub fn new_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// Checks that `signature` is the signature of the nonce, in constant time. The
+/// signature is the HMAC-SHA256 of the nonce keyed with the shared secret,
+/// encoded as hex.
+pub fn verify(secret: &Secret, nonce: &str, signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    let mut mac = new_mac(secret);
+    mac.update(nonce.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn new_mac(secret: &Secret) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret.0.as_bytes()).expect("HMAC accepts keys of any length")
+}
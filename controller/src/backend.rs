@@ -0,0 +1,52 @@
+use crate::transport::Kind;
+use std::future::Future;
+
+/// Runs the servers that the controller spawns, like the [`DockerBackend`]. The brain
+/// is the only one that spawns and stops servers, every other part of the controller
+/// asks the brain to.
+///
+/// [`DockerBackend`]: crate::docker::DockerBackend
+pub trait ServerBackend: Send + 'static {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Starts a server of `kind` named `name`. The server must authenticate with
+    /// the spawn `token`, or the controller won't accept it.
+    fn spawn(
+        &mut self,
+        name: String,
+        kind: Kind,
+        token: String,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Stops a server that was spawned earlier, and cleans up after it. Servers that
+    /// aren't known (anymore) are ignored.
+    ///
+    /// The server is forgotten right away, while stopping it may take a while. So the
+    /// returned future doesn't borrow the backend, and can be run in the background.
+    fn stop(
+        &mut self,
+        name: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send + 'static;
+
+    /// Lists the names of every server that was spawned and hasn't been stopped.
+    fn list(&mut self) -> impl Future<Output = Result<Vec<String>, Self::Error>> + Send;
+
+    /// Describes a server that was spawned earlier, or returns `None` if the server
+    /// isn't known (anymore).
+    fn inspect(
+        &mut self,
+        name: &str,
+    ) -> impl Future<Output = Result<Option<ServerInfo>, Self::Error>> + Send;
+}
+
+/// What a [`ServerBackend`] knows about a server it spawned.
+#[derive(Debug, Clone)]
+pub struct ServerInfo {
+    pub name: String,
+    pub kind: Kind,
+    /// What the backend calls the server, like the ID of its container.
+    pub id: String,
+    /// Whether the server is running, as opposed to e.g. still being created or
+    /// having crashed.
+    pub running: bool,
+}
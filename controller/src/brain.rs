@@ -1,20 +1,45 @@
 // this is... kinda ugly, but w/e
 
-use crate::http::{ComputerStatus, GlobalComputerMap};
+use crate::auth;
+use crate::backend::ServerBackend;
+use crate::config::Config;
+use crate::http::{ComputerStatus, GlobalComputerMap, QueueMetrics};
+use crate::lobby_pool::{Autoscale, LobbyPool, PREFERRED_LOBBY_PRIORITY};
 use crate::minigame_cluster::{ClusterMsg, MinigameClusterHandle, MinigameServer, ServerName};
-use crate::transport::{Kind, Packet, WriteChannel, WriteChannelError};
+use crate::transport::{
+    link_priority, Kind, Packet, Redacted, RequestReply, ServerLink, WriteChannel,
+    WriteChannelError,
+};
 use log::{error, info, trace, warn};
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::sync::mpsc::error::SendError;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// How often the brain checks if lobby servers need to be spawned or stopped, and
+/// whether spawned servers connected in time.
+const LOBBY_AUTOSCALE_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone)]
 pub struct ConnectionInfo {
+    /// Unique for every connection. Servers state their own name and address, so
+    /// two connections can't be told apart by those alone.
+    pub id: u64,
     pub name: String,
     pub kind: Kind,
     pub address: SocketAddr,
+    /// The optional protocol features that were negotiated for the connection.
+    pub features: Vec<String>,
+}
+
+impl ConnectionInfo {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
 }
 
 #[derive(Debug)]
@@ -23,12 +48,23 @@ pub enum BrainMsg {
         conn: ConnectionInfo,
         writer: WriteChannel,
     },
+    /// Sent while a server authenticates, to check that we spawned it under the
+    /// name and kind that it claims, see [`SpawnTokens`]. Whether it did is sent
+    /// back on `valid`, and a server with a valid token must be linked or unlinked
+    /// afterwards.
+    Redeem {
+        conn: ConnectionInfo,
+        token: Option<Redacted>,
+        valid: oneshot::Sender<bool>,
+    },
     Unlink {
         conn: ConnectionInfo,
     },
     Dispatch {
         kind: Kind,
         player: Option<String>,
+        /// Set if the server that sent the request wants to know what happened to it.
+        reply: Option<RequestReply>,
     },
     ClusterForward {
         minigame_kind: String,
@@ -41,6 +77,31 @@ pub enum BrainMsg {
         player: String,
         server: ServerName,
     },
+    PlayerJoined {
+        proxy: String,
+        player: String,
+    },
+    PlayerLeft {
+        proxy: String,
+        player: String,
+    },
+    LobbyPlayerCount {
+        name: String,
+        players: u32,
+    },
+    AutoscaleLobbies,
+    /// Gives up on the spawned servers that didn't connect in time.
+    ExpireSpawns,
+    /// The round-trip latency of a server, measured from its last heartbeat.
+    Latency {
+        name: String,
+        latency: Duration,
+    },
+    StopServer {
+        name: String,
+    },
+    /// Stops every server that was spawned, and then the brain itself.
+    Shutdown,
 }
 
 #[derive(Debug, Error)]
@@ -53,18 +114,33 @@ pub enum BrainError {
     BrainSend(#[from] SendError<BrainMsg>),
     #[error("Write channel error (error sending message to connected server)")]
     WriteChannel(#[from] WriteChannelError),
-    #[error("Docker error")]
-    Docker(#[from] bollard::errors::Error),
 }
 
-pub fn start_brain(computers: GlobalComputerMap) -> UnboundedSender<BrainMsg> {
-    let (sender, receiver) = unbounded_channel();
+/// Starts the brain, which spawns servers with `backend`. The brain runs until it
+/// receives [`BrainMsg::Shutdown`], after which the returned task finishes.
+pub fn start_brain<B: ServerBackend>(
+    config: Config,
+    backend: B,
+    computers: GlobalComputerMap,
+    queues: QueueMetrics,
+) -> (Sender<BrainMsg>, JoinHandle<()>) {
+    let (sender, receiver) = channel(config.brain_queue_size);
+    queues.register("brain", &sender);
 
     let child_sender = sender.clone();
-    tokio::task::spawn(async move {
+    let brain = tokio::task::spawn(async move {
         computers.set_status("brain", ComputerStatus::Online);
 
-        match start(computers.clone(), child_sender, receiver).await {
+        match start(
+            config,
+            backend,
+            computers.clone(),
+            queues,
+            child_sender,
+            receiver,
+        )
+        .await
+        {
             Ok(_) => info!("brain exited successfully!"),
             Err(err) => error!("brain exited unexpectedly: {err:?}"),
         };
@@ -72,129 +148,217 @@ pub fn start_brain(computers: GlobalComputerMap) -> UnboundedSender<BrainMsg> {
         computers.set_status("brain", ComputerStatus::Offline);
     });
 
-    sender
+    (sender, brain)
 }
 
-pub async fn start(
+pub async fn start<B: ServerBackend>(
+    config: Config,
+    mut backend: B,
     computers: GlobalComputerMap,
-    sender: UnboundedSender<BrainMsg>,
-    mut receiver: UnboundedReceiver<BrainMsg>,
+    queues: QueueMetrics,
+    sender: Sender<BrainMsg>,
+    mut receiver: Receiver<BrainMsg>,
 ) -> Result<(), BrainError> {
     info!("brain thread started");
 
     let mut used_names = UniqueNameSet::default();
+    let mut spawn_tokens = SpawnTokens::new(config.spawn_timeout);
 
-    // For simplicity, we assume we'll always have an always-online proxy connection.
+    // We need at least one proxy connection to be able to transport players
+    // anywhere. Multiple proxies may connect (e.g. behind a load balancer), and
+    // they may come and go. While no proxy is connected, we buffer anything that
+    // would transport a player, and handle it once a proxy (re)connects.
     //
-    // In a more robust solution, we'd likely support multiple proxies which can come
-    // in and out of being connected.
-    //
-    // However, for this simple example, we can assume that if we lose the connection
-    // to our proxy (which should be on the same machine), that something so terrible
-    // has happened that it's worth it to abandon ship. Let docker health checks deal
-    // with it (although, those aren't implemented here).
-
-    info!("waiting for proxy connection...");
+    // Servers may still connect and disconnect while no proxy is around. The
+    // `ProxySet` remembers which servers are linked, so that every proxy that
+    // connects learns about every server.
 
     let mut buffer = VecDeque::new();
-    let mut proxy_server = None;
+    let mut proxies = ProxySet::default();
 
-    while let Some(msg) = receiver.recv().await {
-        println!("received a {msg:?}");
+    // Messages the brain sends to itself. These are handled before anything else
+    // in the queue, as the brain can't wait for room in its own queue.
+    let mut followups = VecDeque::new();
 
-        let BrainMsg::NewConn { conn, writer } = msg else {
-            trace!("brain: did not receive connection, queueing into buffer");
-            buffer.push_back(msg);
-            continue;
-        };
+    let mut minigame_servers = MacroCluster::new(config.clone(), sender.clone(), queues);
 
-        let Kind::Proxy = conn.kind else {
-            trace!("brain: did not receive proxy connection, queueing into buffer");
-            buffer.push_back(BrainMsg::NewConn { conn, writer });
-            continue;
-        };
+    let mut lobbies = LobbyPool::new(&config);
 
-        proxy_server = Some(writer);
-        computers.set_status("proxy", ComputerStatus::Online);
-        used_names.record("proxy");
-        break;
-    }
+    // Used to keep the connection to the limbo server alive. The limbo server is
+    // where players wait while no lobby server is online.
+    let mut limbo_server: Option<(String, WriteChannel)> = None;
 
-    let Some(mut proxy_server) = proxy_server else {
-        unreachable!("brain: unable to find a proxy server, wtf?");
-    };
+    // Spawn the limbo server, so that players have somewhere to go as a last resort
+    followups.push_back(BrainMsg::Spawn { kind: Kind::Limbo });
 
-    for queued_msg in buffer {
-        sender.send(queued_msg)?;
+    // Spawn the lobby servers so that players will join to the server somewhere
+    for _ in 0..lobbies.reserve_missing() {
+        followups.push_back(BrainMsg::Spawn { kind: Kind::Lobby });
     }
 
-    // Now that we've established a connection to a proxy server,
-    // let's proceed to handle logic for the rest of the server.
+    // Periodically check if we need more or less lobby servers, and whether every
+    // spawned server connected in time
+    let autoscale_sender = sender.clone();
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(LOBBY_AUTOSCALE_INTERVAL);
 
-    let mut minigame_servers = MacroCluster::new(sender.clone());
+        loop {
+            interval.tick().await;
 
-    // Used to keep the connection to the lobby server alive
-    let mut lobby_server = None;
+            let autoscale = autoscale_sender.send(BrainMsg::AutoscaleLobbies).await;
+            let expire = autoscale_sender.send(BrainMsg::ExpireSpawns).await;
 
-    let docker = ContainerSpawner::new()?;
+            if autoscale.is_err() || expire.is_err() {
+                break;
+            }
+        }
+    });
+
+    info!("waiting for proxy connection...");
+
+    loop {
+        let mut msg = match followups.pop_front() {
+            Some(msg) => msg,
+            None => match receiver.recv().await {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
 
-    while let Some(msg) = receiver.recv().await {
         trace!("brain: handling {msg:?}");
 
+        if proxies.is_empty()
+            && matches!(msg, BrainMsg::Dispatch { .. } | BrainMsg::Transport { .. })
+        {
+            trace!("brain: no proxy connected, queueing into buffer");
+
+            if let BrainMsg::Dispatch {
+                reply: Some(reply), ..
+            } = &mut msg
+            {
+                reply.queued();
+            }
+
+            buffer.push_back(msg);
+            continue;
+        }
+
         match msg {
-            BrainMsg::NewConn {
-                mut writer,
-                conn:
-                    ConnectionInfo {
-                        address,
-                        name,
-                        kind,
-                    },
-            } => {
-                // Due to the simplistic guarantee of "one proxy server will always
-                // be available" that we established earlier, we don't support multiple
-                // proxy servers yet.
-                if matches!(kind, Kind::Proxy) {
-                    warn!("brain: a second proxy server tried to join, not handling this");
-                    writer.shutdown().await?;
+            BrainMsg::NewConn { writer, conn } => {
+                if matches!(conn.kind, Kind::Proxy) {
+                    if proxies.contains(&conn.name) {
+                        warn!(
+                            "brain: a proxy server named {} already exists, not handling this",
+                            conn.name
+                        );
+                        writer.close();
+                        continue;
+                    }
+
+                    computers.set_status(&conn.name, ComputerStatus::Online);
+                    used_names.record(&conn.name);
+                    proxies.insert(conn, writer);
+
+                    // we may have been waiting for a proxy, so handle everything
+                    // we couldn't handle without one
+                    followups.extend(buffer.drain(..));
+
                     continue;
                 }
 
+                // every other server redeemed its spawn token while authenticating
+                let ConnectionInfo {
+                    address,
+                    name,
+                    kind,
+                    ..
+                } = conn;
+
+                // the name was already recorded as used when the server was spawned
                 computers.set_status(&name, ComputerStatus::Online);
-                if !used_names.record(&name) {
-                    warn!("brain: name {name} already exists in used_names {used_names:?}");
-                }
 
-                let priority = kind.priority();
+                let priority = link_priority(&kind);
+                let is_lobby = matches!(kind, Kind::Lobby);
 
                 match kind {
-                    Kind::Lobby => {
-                        // Prevent the writer from getting dropped, and thus the connection stays alive
-                        lobby_server = Some(writer);
+                    Kind::Lobby => lobbies.push(name.clone(), writer),
+                    Kind::Limbo => {
+                        if let Some((limbo, _)) = &limbo_server {
+                            warn!("brain: replacing limbo server {limbo} with {name}");
+                        }
+
+                        limbo_server = Some((name.clone(), writer));
                     }
                     Kind::Minigame { kind } => {
-                        let server = MinigameServer {
-                            writer,
-                            name: name.clone(),
-                            active: true,
-                        };
+                        let server = MinigameServer::new(name.clone(), writer);
 
-                        minigame_servers.cluster_of(&kind).push_server(server)?;
+                        minigame_servers
+                            .cluster_of(&kind)
+                            .push_server(server)
+                            .await?;
                     }
-                    _ => unreachable!("no other lobby kind supported atmz"),
+                    Kind::Proxy => unreachable!("proxies are handled above"),
                 };
 
-                proxy_server
-                    .write_next(&Packet::LinkServer {
-                        name,
-                        address: address.ip().to_string(),
-                        port: address.port(),
-                        priority,
-                    })
-                    .await?;
+                proxies.link(ServerLink {
+                    name: name.clone(),
+                    address: address.ip().to_string(),
+                    port: address.port(),
+                    priority,
+                });
+
+                // now that the lobby is linked, send everyone waiting on it
+                if is_lobby {
+                    for (player, reply) in lobbies.drain_queue() {
+                        // requests without a player only waited for a lobby to be online
+                        let Some(player) = player else {
+                            if let Some(reply) = reply {
+                                reply.fulfilled(name.clone());
+                            }
+
+                            continue;
+                        };
+
+                        let lobby = lobbies.place().expect("a lobby was just added");
+
+                        if let Some(reply) = reply {
+                            reply.fulfilled(lobby.clone());
+                        }
+
+                        let server = ServerName(lobby);
+                        followups.push_back(BrainMsg::Transport { player, server });
+                    }
+
+                    update_preferred_lobby(&mut lobbies, &mut proxies);
+                }
+            }
+            BrainMsg::Redeem { conn, token, valid } => {
+                let redeemed = spawn_tokens.redeem(token.as_ref().map(Redacted::as_str), &conn);
+
+                if !redeemed {
+                    warn!("brain: {conn:?} doesn't have a valid spawn token, rejecting it");
+                }
+
+                // nobody waits for the answer once the controller is shutting down
+                let _ = valid.send(redeemed);
             }
             BrainMsg::Unlink { conn } => {
                 let conn2 = conn.clone();
+
+                // A proxy that was rejected for having a duplicate name will still
+                // unlink itself, so make sure we only forget about the proxy we know.
+                if matches!(conn.kind, Kind::Proxy) && !proxies.remove(&conn) {
+                    warn!("brain: unlinking unknown proxy {conn:?}, ignoring");
+                    continue;
+                }
+
+                // The same goes for servers that were rejected for not having a
+                // valid spawn token.
+                if !matches!(conn.kind, Kind::Proxy) && !spawn_tokens.disconnect(&conn) {
+                    warn!("brain: unlinking unknown server {conn:?}, ignoring");
+                    continue;
+                }
+
                 let ConnectionInfo { name, kind, .. } = conn;
 
                 computers.set_status(&name, ComputerStatus::Offline);
@@ -202,31 +366,140 @@ pub async fn start(
                     warn!("brain: tried to remove name {name} from used_names but it never existed? {used_names:?}");
                 }
 
-                // Due to the simplistic guarantee of "one proxy server will always
-                // be available" that we established earlier, we must do this.
                 if matches!(kind, Kind::Proxy) {
-                    panic!("brain: proxy server died, cannot continue");
+                    if proxies.is_empty() {
+                        warn!("brain: every proxy server died, waiting for proxy connection...");
+                    }
+
+                    continue;
+                }
+
+                let is_lobby = lobbies.remove(&name);
+                let is_limbo = matches!(&limbo_server, Some((limbo, _)) if limbo == &name);
+
+                proxies.unlink(name.clone());
+
+                // its spawn token is used up, so the server can never connect
+                // again. don't leave its container running
+                stop_server(&mut backend, &name);
+
+                // always keep a limbo server around
+                if is_limbo {
+                    limbo_server = None;
+                    followups.push_back(BrainMsg::Spawn { kind: Kind::Limbo });
                 }
 
-                proxy_server
-                    .write_next(&Packet::UnlinkServer { name })
-                    .await?;
+                if is_lobby {
+                    update_preferred_lobby(&mut lobbies, &mut proxies);
+
+                    for _ in 0..lobbies.reserve_missing() {
+                        followups.push_back(BrainMsg::Spawn { kind: Kind::Lobby });
+                    }
+                }
 
                 if let Kind::Minigame { kind } = kind {
                     if let Some(cluster) = minigame_servers.try_get(&kind.clone()) {
-                        cluster.pop_server(conn2)?;
+                        cluster.pop_server(conn2).await?;
                     } else {
                         warn!("when removing minigame ({conn2:?}), a minigame cluster for this kind ({kind}) did not exist.");
                     }
                 }
             }
-            BrainMsg::Dispatch { kind, player } => {
+            BrainMsg::Dispatch {
+                kind,
+                player,
+                mut reply,
+            } => {
                 match kind {
-                    Kind::Limbo | Kind::Proxy => warn!("request to spawn {kind:?} denied"),
-                    Kind::Lobby => todo!("dispatch to lobby"),
+                    Kind::Proxy => {
+                        warn!("request to spawn {kind:?} denied");
+
+                        if let Some(reply) = reply {
+                            reply.failed("proxies can't be requested".to_owned());
+                        }
+                    }
+                    Kind::Limbo => match (&limbo_server, player) {
+                        (Some((name, _)), player) => {
+                            if let Some(reply) = reply {
+                                reply.fulfilled(name.clone());
+                            }
+
+                            if let Some(player) = player {
+                                let server = ServerName(name.clone());
+                                followups.push_back(BrainMsg::Transport { player, server });
+                            }
+                        }
+                        (None, player) => {
+                            warn!("brain: no limbo online to send {player:?} to");
+
+                            if let Some(reply) = reply {
+                                reply.failed("no limbo server is online".to_owned());
+                            }
+                        }
+                    },
+                    Kind::Lobby => {
+                        if let Some(player) = player {
+                            let Some(name) = lobbies.place() else {
+                                trace!(
+                                    "brain: no lobby online, queueing {player} for the next lobby"
+                                );
+
+                                // don't leave the player stranded while they wait
+                                if let Some((limbo, _)) = &limbo_server {
+                                    let server = ServerName(limbo.clone());
+                                    let player = player.clone();
+                                    followups.push_back(BrainMsg::Transport { player, server });
+                                }
+
+                                if let Some(reply) = &mut reply {
+                                    reply.queued();
+                                }
+
+                                lobbies.queue(Some(player), reply);
+
+                                if !lobbies.is_starting() {
+                                    lobbies.reserve();
+                                    followups.push_back(BrainMsg::Spawn { kind: Kind::Lobby });
+                                }
+
+                                continue;
+                            };
+
+                            if let Some(reply) = reply {
+                                reply.fulfilled(name.clone());
+                            }
+
+                            let server = ServerName(name);
+                            followups.push_back(BrainMsg::Transport { player, server });
+                            continue;
+                        }
+
+                        if lobbies.is_empty() && !lobbies.is_starting() {
+                            lobbies.reserve();
+                            followups.push_back(BrainMsg::Spawn { kind: Kind::Lobby });
+                        }
+
+                        // nobody needs to go anywhere, so the request is fulfilled
+                        // as soon as there's a lobby
+                        match (reply, lobbies.preferred()) {
+                            (Some(reply), Some(lobby)) => reply.fulfilled(lobby.to_owned()),
+                            (Some(mut reply), None) => {
+                                reply.queued();
+                                lobbies.queue(None, Some(reply));
+                            }
+                            (None, _) => {}
+                        }
+                    }
                     Kind::Minigame { kind } => {
                         let sender = sender.clone();
-                        dispatch_to_minigame_server(&mut minigame_servers, kind, sender, player)?;
+                        dispatch_to_minigame_server(
+                            &mut minigame_servers,
+                            kind,
+                            sender,
+                            player,
+                            reply,
+                        )
+                        .await?;
                     }
                 };
             }
@@ -236,24 +509,105 @@ pub async fn start(
                     continue;
                 };
 
-                cluster.write.send(msg)?;
+                cluster.write.send(msg).await?;
             }
             BrainMsg::Spawn { kind } => {
                 let server_name = used_names.next_free_name(&kind);
+                let token = spawn_tokens.mint(&server_name, &kind, Instant::now());
 
                 computers.set_status(&server_name, ComputerStatus::Starting);
 
-                docker.spawn(server_name, kind).await?;
-
-                info!("brain: started container!");
+                match backend
+                    .spawn(server_name.clone(), kind, token.clone())
+                    .await
+                {
+                    Ok(()) => info!("brain: started server!"),
+                    Err(err) => {
+                        // the server can't connect, so give up on it right away
+                        warn!("brain: couldn't spawn server {server_name}: {err}");
+                        spawn_tokens.revoke(&token);
+                        followups.push_back(BrainMsg::ExpireSpawns);
+                    }
+                }
             }
             BrainMsg::Transport {
                 player,
                 server: ServerName(to),
             } => {
-                proxy_server
-                    .write_next(&Packet::TransportPlayer { player, to })
-                    .await?;
+                proxies.transport(player, to);
+            }
+            BrainMsg::PlayerJoined { proxy, player } => {
+                proxies.player_joined(proxy, player);
+            }
+            BrainMsg::PlayerLeft { proxy, player } => {
+                proxies.player_left(&proxy, &player);
+            }
+            BrainMsg::LobbyPlayerCount { name, players } => {
+                lobbies.set_players(&name, players);
+                update_preferred_lobby(&mut lobbies, &mut proxies);
+            }
+            BrainMsg::StopServer { name } => {
+                // unlink the server first, so that nobody gets sent to it anymore
+                proxies.unlink(name.clone());
+                stop_server(&mut backend, &name);
+            }
+            BrainMsg::Latency { name, latency } => {
+                computers.set_latency(&name, latency);
+            }
+            BrainMsg::AutoscaleLobbies => {
+                let Autoscale { spawn, stop } = lobbies.autoscale(Instant::now());
+
+                if spawn {
+                    followups.push_back(BrainMsg::Spawn { kind: Kind::Lobby });
+                }
+
+                for name in stop {
+                    proxies.unlink(name.clone());
+                    stop_server(&mut backend, &name);
+                }
+
+                update_preferred_lobby(&mut lobbies, &mut proxies);
+            }
+            BrainMsg::ExpireSpawns => {
+                for (name, kind) in spawn_tokens.expire(Instant::now()) {
+                    warn!("brain: {name} didn't connect in time, giving up on it");
+
+                    computers.set_status(&name, ComputerStatus::Offline);
+                    used_names.unrecord(&name);
+                    stop_server(&mut backend, &name);
+
+                    // whatever the server was spawned for still needs a server
+                    match kind {
+                        Kind::Lobby => {
+                            lobbies.release();
+
+                            for _ in 0..lobbies.reserve_missing() {
+                                followups.push_back(BrainMsg::Spawn { kind: Kind::Lobby });
+                            }
+
+                            // players may be waiting on the lobby, even without a minimum
+                            if lobbies.has_queue() && !lobbies.is_starting() {
+                                lobbies.reserve();
+                                followups.push_back(BrainMsg::Spawn { kind: Kind::Lobby });
+                            }
+                        }
+                        Kind::Limbo if limbo_server.is_none() => {
+                            followups.push_back(BrainMsg::Spawn { kind: Kind::Limbo });
+                        }
+                        Kind::Limbo => {}
+                        Kind::Minigame { kind } => {
+                            if let Some(cluster) = minigame_servers.try_get(&kind) {
+                                cluster.write.send(ClusterMsg::SpawnExpired).await?;
+                            }
+                        }
+                        Kind::Proxy => unreachable!("proxies are never spawned"),
+                    }
+                }
+            }
+            BrainMsg::Shutdown => {
+                info!("brain: shutting down, stopping every server");
+                stop_all(&mut backend).await;
+                break;
             }
         }
     }
@@ -262,21 +616,82 @@ pub async fn start(
     Ok(())
 }
 
-fn dispatch_to_minigame_server(
+/// Stops a server in the background, as stopping a server can take a while. A server
+/// that can't be stopped is only logged, there's nothing else the brain can do about it.
+fn stop_server<B: ServerBackend>(backend: &mut B, name: &str) -> JoinHandle<()> {
+    let stopping = backend.stop(name);
+    let name = name.to_owned();
+
+    tokio::task::spawn(async move {
+        if let Err(err) = stopping.await {
+            warn!("brain: couldn't stop server {name}: {err}");
+        }
+    })
+}
+
+/// Stops every server that the backend still knows about, as they won't be able to
+/// connect to anyone anymore.
+async fn stop_all<B: ServerBackend>(backend: &mut B) {
+    let servers = match backend.list().await {
+        Ok(servers) => servers,
+        Err(err) => {
+            warn!("brain: couldn't list the servers to stop: {err}");
+            return;
+        }
+    };
+
+    let stopping: Vec<_> = servers
+        .iter()
+        .map(|name| stop_server(backend, name))
+        .collect();
+
+    for stopping in stopping {
+        // failures were logged by the task itself
+        let _ = stopping.await;
+    }
+}
+
+/// Links the lobby that players should be placed into at a higher priority than
+/// the other lobbies, so that proxies forward newly joining players to it.
+fn update_preferred_lobby(lobbies: &mut LobbyPool, proxies: &mut ProxySet) {
+    let Some((previous, preferred)) = lobbies.update_preferred() else {
+        return;
+    };
+
+    trace!("brain: preferring lobby {preferred} over {previous:?}");
+
+    if let Some(previous) = previous {
+        proxies.set_priority(&previous, link_priority(&Kind::Lobby));
+    }
+
+    proxies.set_priority(&preferred, PREFERRED_LOBBY_PRIORITY);
+}
+
+async fn dispatch_to_minigame_server(
     minigame_servers: &mut MacroCluster,
     kind: String,
-    sender: UnboundedSender<BrainMsg>,
+    sender: Sender<BrainMsg>,
     player: Option<String>,
+    mut reply: Option<RequestReply>,
 ) -> Result<(), BrainError> {
     trace!("brain: initiating queue request of minigame {kind}");
 
-    let server_name = minigame_servers.cluster_of(&kind).queue_server()?;
+    let server_name = minigame_servers.cluster_of(&kind).queue_server().await?;
+
+    // finding a server takes at least one round of pings
+    if let Some(reply) = &mut reply {
+        reply.queued();
+    }
 
     tokio::task::spawn(async move {
         trace!("brain dispatch task ({kind}, {player:?}): waiting for server_name...");
         let server_name = server_name.await;
         trace!("brain dispatch task ({kind}, {player:?}): got server: {server_name}");
 
+        if let Some(reply) = reply {
+            reply.fulfilled(server_name.0.clone());
+        }
+
         let Some(player_name) = player else { return };
 
         trace!("brain dispatch task ({kind}, {player_name:?}): transporting {player_name} to {server_name}");
@@ -286,6 +701,7 @@ fn dispatch_to_minigame_server(
                 player: player_name,
                 server: server_name,
             })
+            .await
             .unwrap();
     });
 
@@ -295,15 +711,33 @@ fn dispatch_to_minigame_server(
 /// Responsible for managing clusters of [`MinigameClusterHandle`]s
 pub struct MacroCluster {
     handles: HashMap<String, MinigameClusterHandle>,
-    sender: UnboundedSender<BrainMsg>,
+    config: Config,
+    sender: Sender<BrainMsg>,
+    queues: QueueMetrics,
 }
 
 impl MacroCluster {
-    pub fn new(sender: UnboundedSender<BrainMsg>) -> Self {
-        Self {
+    pub fn new(config: Config, sender: Sender<BrainMsg>, queues: QueueMetrics) -> Self {
+        let mut macro_cluster = Self {
             handles: HashMap::default(),
+            config,
             sender,
+            queues,
+        };
+
+        // clusters are usually started once they're first needed, but clusters
+        // with a warm pool or a minimum amount of servers need to boot their
+        // servers ahead of time
+        let config = &macro_cluster.config;
+        let warm_pool = config.minigame_warm_pool.kinds();
+        let min_servers = config.minigame_min_servers.kinds();
+        let kinds: HashSet<_> = warm_pool.chain(min_servers).cloned().collect();
+
+        for kind in kinds {
+            macro_cluster.cluster_of(kind);
         }
+
+        macro_cluster
     }
 
     pub fn try_get(&mut self, kind: &str) -> Option<&mut MinigameClusterHandle> {
@@ -313,7 +747,145 @@ impl MacroCluster {
     pub fn cluster_of<S: ToString>(&mut self, kind: S) -> &mut MinigameClusterHandle {
         let kind = kind.to_string();
         let entry = self.handles.entry(kind.clone());
-        entry.or_insert_with(|| MinigameClusterHandle::start(kind.to_string(), self.sender.clone()))
+        entry.or_insert_with(|| {
+            let config = self.config.cluster_config(&kind);
+            let sender = self.sender.clone();
+            MinigameClusterHandle::start(kind.to_string(), config, sender, &self.queues)
+        })
+    }
+}
+
+/// Keeps track of every connected proxy server, which proxy each player is on,
+/// and which servers every proxy should have linked.
+#[derive(Default)]
+pub struct ProxySet {
+    proxies: HashMap<String, ProxyConnection>,
+    /// Maps a player's UUID to the name of the proxy they are connected to.
+    players: HashMap<String, String>,
+    /// Every server that has been linked, so that proxies that connect later on
+    /// can be told about them. BTreeMap for stable order
+    servers: BTreeMap<String, ServerLink>,
+}
+
+struct ProxyConnection {
+    id: u64,
+    writer: WriteChannel,
+}
+
+impl ProxySet {
+    pub fn is_empty(&self) -> bool {
+        self.proxies.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.proxies.contains_key(name)
+    }
+
+    /// Adds a proxy, and syncs every currently linked server to it.
+    pub fn insert(&mut self, conn: ConnectionInfo, writer: WriteChannel) {
+        info!("brain: proxy {} connected", conn.name);
+
+        let servers = self.servers.values().cloned();
+
+        // proxies that can't sync servers just get every server linked one by one
+        let packets: Vec<Packet> = match conn.supports("sync-servers") {
+            true => vec![Packet::SyncServers {
+                servers: servers.collect(),
+            }],
+            false => servers.map(Packet::from).collect(),
+        };
+
+        for packet in packets {
+            if let Err(err) = writer.send(&packet) {
+                warn!(
+                    "brain: couldn't send {packet:?} to proxy {}: {err}",
+                    conn.name
+                );
+            }
+        }
+
+        let id = conn.id;
+        self.proxies
+            .insert(conn.name, ProxyConnection { id, writer });
+    }
+
+    /// Removes the proxy described by `conn`, along with all players on it. Returns
+    /// `false` if the proxy was not known (or is a different connection with the same name).
+    pub fn remove(&mut self, conn: &ConnectionInfo) -> bool {
+        match self.proxies.get(&conn.name) {
+            Some(proxy) if proxy.id == conn.id => {}
+            _ => return false,
+        };
+
+        info!("brain: proxy {} disconnected", conn.name);
+        self.proxies.remove(&conn.name);
+        self.players.retain(|_, proxy| proxy != &conn.name);
+        true
+    }
+
+    pub fn player_joined(&mut self, proxy: String, player: String) {
+        trace!("brain: player {player} is on proxy {proxy}");
+        self.players.insert(player, proxy);
+    }
+
+    pub fn player_left(&mut self, proxy: &str, player: &str) {
+        // the player may have already joined another proxy before this proxy noticed
+        if self.players.get(player).map(String::as_str) == Some(proxy) {
+            self.players.remove(player);
+        }
+    }
+
+    /// Links a server on every proxy, including proxies that connect later on.
+    pub fn link(&mut self, server: ServerLink) {
+        self.broadcast(&server.clone().into());
+        self.servers.insert(server.name.clone(), server);
+    }
+
+    /// Links an already linked server on every proxy again, with a different priority.
+    pub fn set_priority(&mut self, name: &str, priority: u16) {
+        let Some(server) = self.servers.get_mut(name) else {
+            return;
+        };
+
+        server.priority = priority;
+        let packet = server.clone().into();
+        self.broadcast(&packet);
+    }
+
+    /// Unlinks a server on every proxy.
+    pub fn unlink(&mut self, name: String) {
+        if self.servers.remove(&name).is_none() {
+            return;
+        }
+
+        self.broadcast(&Packet::UnlinkServer { name });
+    }
+
+    /// Sends a packet to every proxy. A proxy that can't be written to is skipped,
+    /// as its connection will be unlinked shortly anyway.
+    pub fn broadcast(&mut self, packet: &Packet) {
+        for (name, proxy) in self.proxies.iter() {
+            if let Err(err) = proxy.writer.send(packet) {
+                warn!("brain: couldn't send {packet:?} to proxy {name}: {err}");
+            }
+        }
+    }
+
+    /// Sends a [`Packet::TransportPlayer`] to the proxy the player is on, or to
+    /// every proxy if we don't know which proxy that is.
+    pub fn transport(&mut self, player: String, to: String) {
+        let proxy = self.players.get(&player).cloned();
+        let packet = Packet::TransportPlayer { player, to };
+
+        let Some(proxy) = proxy.and_then(|name| self.proxies.get(&name)) else {
+            trace!("brain: unknown proxy for player, sending transport to every proxy");
+            self.broadcast(&packet);
+            return;
+        };
+
+        if let Err(err) = proxy.writer.send(&packet) {
+            warn!("brain: couldn't send {packet:?} to proxy: {err}");
+        }
     }
 }
 
@@ -346,83 +918,215 @@ impl UniqueNameSet {
     }
 }
 
-use bollard::container::Config;
-use bollard::errors::Error;
-use bollard::network::ConnectNetworkOptions;
-use bollard::service::EndpointSettings;
-use bollard::Docker;
+/// Binds every spawned server to the name and kind it was spawned with. Every
+/// spawn gets a one-time token, which the server must present when it connects,
+/// so that servers can't claim the name of another server. A token that isn't
+/// presented in time expires.
+#[derive(Debug)]
+pub struct SpawnTokens {
+    /// How long a spawned server has to connect.
+    timeout: Duration,
+    /// Maps the token of every spawned server that hasn't connected yet to the
+    /// name and kind it was spawned with.
+    pending: HashMap<String, PendingSpawn>,
+    /// Maps the name of every server that connected with a valid token to the id of
+    /// its connection.
+    connected: HashMap<String, u64>,
+}
 
-struct ContainerSpawner {
-    docker: Docker,
+#[derive(Debug)]
+struct PendingSpawn {
+    name: String,
+    kind: Kind,
+    /// When the token expires.
+    deadline: Instant,
 }
 
-impl ContainerSpawner {
-    pub fn new() -> Result<Self, Error> {
-        let docker = Docker::connect_with_unix_defaults()?;
-        Ok(Self { docker })
+impl SpawnTokens {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            pending: HashMap::new(),
+            connected: HashMap::new(),
+        }
     }
 
-    pub async fn spawn(&self, server_name: String, kind: Kind) -> Result<(), Error> {
-        let mut env = Vec::new();
+    /// Creates the token for a server that is about to be spawned at `now`.
+    pub fn mint(&mut self, name: &str, kind: &Kind, now: Instant) -> String {
+        let token = auth::new_token();
+        let spawn = PendingSpawn {
+            name: name.to_owned(),
+            kind: kind.clone(),
+            deadline: now + self.timeout,
+        };
 
-        env.push("CONTROLLER_IP=controller".to_owned());
-        env.push(format!("SERVER_NAME={server_name}"));
+        self.pending.insert(token.clone(), spawn);
+        token
+    }
 
-        match kind.clone() {
-            Kind::Proxy => unimplemented!("cannot spawn new proxy"),
-            Kind::Limbo => {
-                env.push("SERVER_KIND=Limbo".to_owned());
-            }
-            Kind::Lobby => {
-                env.push("SERVER_KIND=Lobby".to_owned());
-            }
-            Kind::Minigame { kind } => {
-                env.push("SERVER_KIND=Minigame".to_owned());
-                env.push(format!("MINIGAME_KIND={kind}"));
+    /// Makes a token expire right away, e.g. because its server couldn't be spawned.
+    pub fn revoke(&mut self, token: &str) {
+        if let Some(spawn) = self.pending.get_mut(token) {
+            spawn.deadline = Instant::now();
+        }
+    }
+
+    /// Forgets about the tokens whose server didn't connect before `now`, and returns
+    /// the name and kind of those servers.
+    pub fn expire(&mut self, now: Instant) -> Vec<(String, Kind)> {
+        let mut expired = Vec::new();
+
+        self.pending.retain(|_, spawn| {
+            if spawn.deadline > now {
+                return true;
             }
-        };
 
-        let image = match kind {
-            Kind::Lobby => "ems-lobby",
-            Kind::Minigame { .. } => "ems-minigame",
-            _ => unimplemented!(),
-        };
+            expired.push((spawn.name.clone(), spawn.kind.clone()));
+            false
+        });
+
+        expired
+    }
 
-        let opts = Config {
-            env: Some(env),
-            image: Some(image.to_owned()),
-            ..Default::default()
+    /// Uses up the token of a server that connected. Returns `false` if the token
+    /// wasn't minted for the name and kind that the server claims.
+    pub fn redeem(&mut self, token: Option<&str>, conn: &ConnectionInfo) -> bool {
+        let Some(token) = token else {
+            return false;
         };
 
-        trace!("brain: spawning child...");
-        let container = self
-            .docker
-            .create_container::<String, _>(None, opts)
-            .await?;
+        match self.pending.get(token) {
+            Some(spawn) if spawn.name == conn.name && spawn.kind == conn.kind => {}
+            _ => return false,
+        }
+
+        self.pending.remove(token);
+        self.connected.insert(conn.name.clone(), conn.id);
+
+        trace!("brain: {} redeemed its spawn token", conn.name);
+        true
+    }
+
+    /// Forgets about a server that disconnected. Returns `false` if the server
+    /// never redeemed a token (or is a different connection with the same name).
+    pub fn disconnect(&mut self, conn: &ConnectionInfo) -> bool {
+        match self.connected.get(&conn.name) {
+            Some(id) if *id == conn.id => {
+                self.connected.remove(&conn.name);
+                true
+            }
+            _ => false,
+        }
+    }
+}
 
-        for warning in container.warnings {
-            trace!("brain: warning {warning}");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller_client::codec::MessagePack;
+    use std::sync::Arc;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn conn(name: &str, kind: Kind, id: u64) -> ConnectionInfo {
+        ConnectionInfo {
+            id,
+            name: name.to_owned(),
+            kind,
+            address: SocketAddr::from(([127, 0, 0, 1], 25565)),
+            features: Vec::new(),
         }
+    }
 
-        let id = container.id;
-        info!("brain: spawned server {id}\n");
+    /// A writer that discards everything. Needs a runtime, as it runs in its own task.
+    fn writer() -> WriteChannel {
+        let addr = ([127, 0, 0, 1], 25565).into();
+        WriteChannel::new(tokio::io::sink(), addr, Arc::new(MessagePack), 8)
+    }
 
-        self.docker
-            .connect_network(
-                "ems_network",
-                ConnectNetworkOptions {
-                    container: &id,
-                    endpoint_config: EndpointSettings {
-                        ..Default::default()
-                    },
-                },
-            )
-            .await?;
+    #[tokio::test]
+    async fn only_the_proxy_that_connected_is_removed() {
+        let mut proxies = ProxySet::default();
+        let proxy = conn("proxy", Kind::Proxy, 0);
+        proxies.insert(proxy.clone(), writer());
+
+        // a proxy that was rejected for stating the same name and address
+        assert!(!proxies.remove(&conn("proxy", Kind::Proxy, 1)));
+        assert!(proxies.contains("proxy"));
+
+        assert!(proxies.remove(&proxy));
+        assert!(proxies.is_empty());
+    }
+
+    #[test]
+    fn redeems_a_token_once() {
+        let mut tokens = SpawnTokens::new(TIMEOUT);
+        let token = tokens.mint("lobby-0", &Kind::Lobby, Instant::now());
+        let lobby = conn("lobby-0", Kind::Lobby, 0);
+
+        assert!(tokens.redeem(Some(&token), &lobby));
+        assert!(!tokens.redeem(Some(&token), &lobby));
+    }
+
+    #[test]
+    fn rejects_a_token_minted_for_another_server() {
+        let mut tokens = SpawnTokens::new(TIMEOUT);
+        let token = tokens.mint("lobby-0", &Kind::Lobby, Instant::now());
+
+        assert!(!tokens.redeem(Some(&token), &conn("lobby-1", Kind::Lobby, 0)));
+        assert!(!tokens.redeem(Some(&token), &conn("lobby-0", Kind::Limbo, 0)));
+        assert!(!tokens.redeem(Some("forged"), &conn("lobby-0", Kind::Lobby, 0)));
+        assert!(!tokens.redeem(None, &conn("lobby-0", Kind::Lobby, 0)));
+
+        // failed attempts don't use up the token
+        assert!(tokens.redeem(Some(&token), &conn("lobby-0", Kind::Lobby, 0)));
+    }
+
+    #[test]
+    fn only_the_server_that_redeemed_the_token_disconnects() {
+        let mut tokens = SpawnTokens::new(TIMEOUT);
+        let token = tokens.mint("lobby-0", &Kind::Lobby, Instant::now());
+        let lobby = conn("lobby-0", Kind::Lobby, 0);
+
+        assert!(!tokens.disconnect(&lobby));
+        assert!(tokens.redeem(Some(&token), &lobby));
+        assert!(!tokens.disconnect(&conn("lobby-0", Kind::Lobby, 1)));
+        assert!(tokens.disconnect(&lobby));
+        assert!(!tokens.disconnect(&lobby));
+    }
 
-        info!("brain: connected new server to network!");
+    #[test]
+    fn expires_tokens_after_the_timeout() {
+        let mut tokens = SpawnTokens::new(TIMEOUT);
+        let now = Instant::now();
+        let token = tokens.mint("lobby-0", &Kind::Lobby, now);
+
+        assert!(tokens.expire(now + TIMEOUT / 2).is_empty());
+        assert_eq!(
+            tokens.expire(now + TIMEOUT),
+            [("lobby-0".to_owned(), Kind::Lobby)]
+        );
+        assert!(tokens.expire(now + TIMEOUT * 2).is_empty());
+        assert!(!tokens.redeem(Some(&token), &conn("lobby-0", Kind::Lobby, 0)));
+    }
+
+    #[test]
+    fn redeemed_tokens_dont_expire() {
+        let mut tokens = SpawnTokens::new(TIMEOUT);
+        let now = Instant::now();
+        let token = tokens.mint("lobby-0", &Kind::Lobby, now);
+
+        assert!(tokens.redeem(Some(&token), &conn("lobby-0", Kind::Lobby, 0)));
+        assert!(tokens.expire(now + TIMEOUT).is_empty());
+    }
 
-        self.docker.start_container::<String>(&id, None).await?;
+    #[test]
+    fn revoked_tokens_expire_right_away() {
+        let mut tokens = SpawnTokens::new(TIMEOUT);
+        let limbo = Kind::Limbo;
+        let token = tokens.mint("limbo", &limbo, Instant::now());
 
-        Ok(())
+        tokens.revoke(&token);
+        assert_eq!(tokens.expire(Instant::now()), [("limbo".to_owned(), limbo)]);
     }
 }
@@ -1,129 +1,632 @@
+use crate::auth;
 use crate::brain::ConnectionInfo;
+use crate::config::Config;
 use crate::minigame_cluster::ServerName;
-use crate::transport::{Kind, Packet, ReadChannel, ReadChannelError, WriteChannel};
+use crate::tls::ClientIdentity;
+use crate::transport::{
+    Kind, Packet, ReadChannel, ReadChannelError, RequestReply, WriteChannel, WriteChannelError,
+    FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 use crate::{BrainMsg, ClusterMsg};
+use controller_client::codec::{self, CodecError};
 
 use log::{error, info, trace, warn};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tokio::sync::mpsc::error::SendError;
-use tokio::{net::TcpStream, sync::mpsc::UnboundedSender};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
 
-pub async fn start_client_listener(sender: UnboundedSender<BrainMsg>) {
-    let addr: SocketAddr = ([0, 0, 0, 0], 25550).into();
-    let listener = TcpListener::bind(addr).await.unwrap();
-    info!("listening on {addr}");
+/// The amount of peers that have been disconnected for being malformed, see
+/// [`malformed_peers`].
+static MALFORMED_PEERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the amount of peers that have been disconnected for sending a malformed
+/// frame, or a packet that couldn't be decoded during the handshake, since the
+/// controller started.
+pub fn malformed_peers() -> usize {
+    MALFORMED_PEERS.load(Ordering::Relaxed)
+}
+
+/// Counts a peer that was disconnected for being malformed, and returns the amount
+/// of malformed peers so far.
+pub(crate) fn count_malformed_peer() -> usize {
+    MALFORMED_PEERS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// The amount of requests that were turned away, see [`overloaded_requests`].
+static OVERLOADED_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the amount of requests that were turned away because the brain was
+/// overloaded, since the controller started. Requests without an ID can't be told
+/// about it, so this is the only trace of them.
+pub fn overloaded_requests() -> usize {
+    OVERLOADED_REQUESTS.load(Ordering::Relaxed)
+}
+
+/// The id of the next connection, see [`ConnectionInfo::id`].
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Accepts servers on `listener` and hands them to the brain, until `shutdown` is
+/// set. Once it is, every connection is dropped without unlinking it, as the brain
+/// is about to stop anyway.
+pub async fn start_client_listener(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    sender: Sender<BrainMsg>,
+    config: Config,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    if config.secret.is_none() {
+        warn!("CONTROLLER_SECRET is not set, any server that connects will be trusted");
+    }
+
+    let config = Arc::new(config);
+    let mut clients = JoinSet::new();
 
     // listen for new clients
-    while let Ok((connection, address)) = listener.accept().await {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            // forget about the clients that disconnected
+            Some(_) = clients.join_next(), if !clients.is_empty() => continue,
+            () = shutdown_requested(&mut shutdown) => break,
+        };
+
+        let (connection, address) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                error!("couldn't accept connections anymore: {err}");
+                break;
+            }
+        };
+
         trace!("new connection received: {address}");
 
         let sender = sender.clone();
-        tokio::task::spawn(async move {
-            match handle_client(sender, connection, address).await {
+        let config = config.clone();
+        let acceptor = acceptor.clone();
+        let mut shutdown = shutdown.clone();
+        clients.spawn(async move {
+            let client = async {
+                match acceptor {
+                    Some(acceptor) => match acceptor.accept(connection).await {
+                        Ok(stream) => {
+                            let identity = ClientIdentity::of(&stream);
+                            handle_client(sender, stream, address, &config, identity).await
+                        }
+                        Err(err) => Err(HandleClientError::TlsHandshake(err)),
+                    },
+                    None => handle_client(sender, connection, address, &config, None).await,
+                }
+            };
+
+            let result = tokio::select! {
+                result = client => result.map(|never| never),
+                () = shutdown_requested(&mut shutdown) => Ok(()),
+            };
+
+            match result {
                 Ok(_) => info!("{address}: disconnected"),
+                // dropping the connection closes it, there's nothing more to do
+                Err(error) if error.is_malformed() => {
+                    let count = count_malformed_peer();
+                    warn!("{address}: disconnected for being malformed: {error} ({count} malformed peers so far)");
+                }
                 Err(error) => warn!("{address}: disconnected with error: {error}"),
             };
         });
     }
+
+    info!(
+        "no longer accepting connections, closing {} connections",
+        clients.len()
+    );
+    while clients.join_next().await.is_some() {}
+}
+
+/// Waits until `shutdown` is set. A controller that is never shut down keeps running,
+/// even if nobody is left to shut it down.
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            std::future::pending().await
+        }
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum HandleClientError {
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("TLS handshake failed: {0}")]
+    TlsHandshake(std::io::Error),
     #[error("ReadChannelError: {0}")]
     ChannelError(#[from] ReadChannelError),
+    #[error("Unable to decode handshake packet: {0}")]
+    CodecError(#[from] CodecError),
+    #[error("WriteChannelError: {0}")]
+    WriteChannelError(#[from] WriteChannelError),
     #[error("Did not receive initial authentication packet, instead received: {0:?}")]
     InitialAuthPacket(Packet),
+    #[error("Client speaks protocol version {0}, but only versions {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION} are supported")]
+    IncompatibleVersion(u32),
+    #[error("Did not receive a challenge response, instead received: {0:?}")]
+    ChallengeResponsePacket(Packet),
+    #[error("Client failed the authentication challenge")]
+    ChallengeFailed,
+    #[error("Client certificate doesn't allow authenticating as {0}")]
+    KindNotAllowed(Kind),
+    #[error("Client doesn't have a valid spawn token for {0}")]
+    InvalidSpawnToken(String),
+    #[error("Client stated an invalid address: {0:?}")]
+    InvalidAddress(String),
+    #[error("Client didn't send anything for {0:?}")]
+    Timeout(Duration),
     #[error("Received authentication packet during normal communication: {0:?}")]
     SpuriousPacket(Packet),
+    #[error("Connection was closed for writing")]
+    WriterClosed,
     #[error("Unable to send message to brain")]
     SendBrainError(#[from] SendError<BrainMsg>),
 }
 
-pub async fn handle_client(
-    to_brain: UnboundedSender<BrainMsg>,
-    connection: TcpStream,
+impl HandleClientError {
+    /// Whether the client sent a frame that can't be valid, or a packet that couldn't
+    /// be decoded during the handshake. Either means that the client doesn't speak
+    /// our protocol at all.
+    fn is_malformed(&self) -> bool {
+        matches!(
+            self,
+            HandleClientError::ChannelError(ReadChannelError::MalformedFrame(_))
+                | HandleClientError::CodecError(_)
+        )
+    }
+}
+
+pub async fn handle_client<S>(
+    to_brain: Sender<BrainMsg>,
+    connection: S,
     address: SocketAddr,
-) -> Result<!, HandleClientError> {
+    config: &Config,
+    identity: Option<ClientIdentity>,
+) -> Result<!, HandleClientError>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
     info!("{address}: client connected");
 
-    let (read, write) = connection.into_split();
-    let mut reader = ReadChannel::new(read);
-    let writer = WriteChannel::new(write);
+    let (read, write) = tokio::io::split(connection);
+    let mut reader = ReadChannel::new(read, config.max_frame_size);
+
+    // read authentication packet, which decides what codec the connection uses.
+    // clients that don't send anything would otherwise be kept around forever
+    let timeout = config.heartbeat_timeout;
+    let frame = tokio::time::timeout(timeout, reader.read_frame())
+        .await
+        .map_err(|_| HandleClientError::Timeout(timeout))??;
+    let codec = codec::detect(&frame);
+    reader.set_codec(codec.clone());
 
-    // read authentication packet
-    let packet = reader.read_next().await?;
-    trace!("{address}: initial packet received: {packet:?}");
+    let packet = codec.decode(&frame)?;
+    trace!("{address}: initial packet received ({codec:?}): {packet:?}");
 
-    let Packet::Authentication { name, kind, ip } = packet else {
-        return Err(HandleClientError::InitialAuthPacket(packet))
+    let writer = WriteChannel::new(write, address, codec, config.outbound_queue_size);
+
+    let Packet::Authentication {
+        name,
+        kind,
+        ip,
+        version,
+        features,
+        token,
+    } = packet
+    else {
+        return Err(HandleClientError::InitialAuthPacket(packet));
     };
 
-    let stated_address: SocketAddr = ip
-        .trim_matches('/')
-        .parse()
-        .expect("expected valid IP address");
+    // tell clients that we can't talk to right away, instead of failing on the
+    // first packet that either side doesn't understand
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        let error = HandleClientError::IncompatibleVersion(version);
+
+        let reply = Packet::AuthenticationRejected {
+            version: PROTOCOL_VERSION,
+            reason: error.to_string(),
+        };
+
+        writer.send(&reply)?;
+        return Err(error);
+    }
+
+    // only trust clients that know the shared secret
+    if let Some(secret) = &config.secret {
+        let nonce = auth::new_nonce();
+        writer.send(&Packet::Challenge {
+            nonce: nonce.clone(),
+        })?;
+
+        let packet = read_handshake_packet(&mut reader, timeout).await?;
+        let Packet::ChallengeResponse { signature } = packet else {
+            return Err(HandleClientError::ChallengeResponsePacket(packet));
+        };
+
+        if !auth::verify(secret, &nonce, signature.as_str()) {
+            let error = HandleClientError::ChallengeFailed;
+
+            let reply = Packet::AuthenticationRejected {
+                version: PROTOCOL_VERSION,
+                reason: error.to_string(),
+            };
+
+            writer.send(&reply)?;
+            return Err(error);
+        }
+
+        trace!("{address}: passed the authentication challenge");
+    }
+
+    // when clients need a certificate, it decides what kind of server they can be
+    if config.tls_client_ca.is_some() {
+        let allowed = identity.as_ref().is_some_and(|id| id.allows(&kind));
+
+        if !allowed {
+            let error = HandleClientError::KindNotAllowed(kind);
+
+            let reply = Packet::AuthenticationRejected {
+                version: PROTOCOL_VERSION,
+                reason: error.to_string(),
+            };
+
+            writer.send(&reply)?;
+            return Err(error);
+        }
+    }
+
+    let features: Vec<String> = features
+        .into_iter()
+        .filter(|feature| FEATURES.contains(&feature.as_str()))
+        .collect();
+
+    let Ok(stated_address) = ip.trim_matches('/').parse::<SocketAddr>() else {
+        let error = HandleClientError::InvalidAddress(ip);
+
+        let reply = Packet::AuthenticationRejected {
+            version: PROTOCOL_VERSION,
+            reason: error.to_string(),
+        };
+
+        writer.send(&reply)?;
+        return Err(error);
+    };
 
     let mut conn_address = address;
     conn_address.set_port(stated_address.port());
 
     let conn = ConnectionInfo {
+        id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
         name,
         kind,
         address: conn_address,
+        features,
+    };
+
+    // every server but a proxy must have been spawned by us, under the name and
+    // kind that it claims. spawn tokens can only be used once, so a server that
+    // lost its connection can't come back
+    if !matches!(conn.kind, Kind::Proxy) {
+        let (valid, is_valid) = oneshot::channel();
+        to_brain
+            .send(BrainMsg::Redeem {
+                conn: conn.clone(),
+                token,
+                valid,
+            })
+            .await?;
+
+        if !is_valid.await.unwrap_or(false) {
+            let error = HandleClientError::InvalidSpawnToken(conn.name);
+
+            let reply = Packet::AuthenticationRejected {
+                version: PROTOCOL_VERSION,
+                reason: error.to_string(),
+            };
+
+            writer.send(&reply)?;
+            return Err(error);
+        }
+    }
+
+    trace!(
+        "{address}: using protocol version {version} with features {:?}",
+        conn.features
+    );
+    let reply = Packet::AuthenticationAccepted {
+        version,
+        features: conn.features.clone(),
     };
 
+    // the server may have redeemed its token already, so the brain has to learn
+    // about it even if the client is gone. the connection loop notices that the
+    // writer is closed, and unlinks it right away
+    if let Err(err) = writer.send(&reply) {
+        warn!("{address}: couldn't accept the client: {err}");
+    }
+
+    // the client has to know that compression was agreed on before it receives
+    // a compressed frame, so only start compressing after the reply
+    if conn.supports("compression") {
+        writer.enable_compression(config.compression_threshold);
+    }
+
     trace!("{address}: registering connection as {conn:?}");
-    to_brain.send(BrainMsg::NewConn {
-        writer,
-        conn: conn.clone(),
-    })?;
+    to_brain
+        .send(BrainMsg::NewConn {
+            writer: writer.clone(),
+            conn: conn.clone(),
+        })
+        .await?;
+
+    // keep telling the client that we're alive, and measure how long it takes to answer.
+    // the heartbeats are sent from this task, so that they stop along with the connection
+    let epoch = Instant::now();
+    let heartbeats = send_heartbeats(writer.clone(), epoch, config.heartbeat_interval);
 
     info!("{address}: ready, listening for messages");
 
-    let result = read_packets(address, reader, &to_brain, &conn).await;
+    let result = tokio::select! {
+        result = read_packets(address, reader, &writer, &to_brain, &conn, epoch, timeout) => result,
+        // the writer stops when the client can't keep up or the brain closes the connection,
+        // and heartbeats can only fail to be sent once the writer stopped
+        () = writer.closed() => Err(HandleClientError::WriterClosed),
+        () = heartbeats => Err(HandleClientError::WriterClosed),
+    };
 
     warn!("{address}: connection loop failed, {result:?}");
 
-    to_brain.send(BrainMsg::Unlink { conn })?;
+    to_brain.send(BrainMsg::Unlink { conn }).await?;
 
     result
 }
 
+/// Reads the next packet of the handshake, which must arrive within `timeout`. A
+/// packet that can't be decoded is a [`HandleClientError::CodecError`], just like
+/// an initial packet that can't be.
+async fn read_handshake_packet<R: AsyncRead + Unpin>(
+    reader: &mut ReadChannel<R>,
+    timeout: Duration,
+) -> Result<Packet, HandleClientError> {
+    let read = tokio::time::timeout(timeout, reader.read_next())
+        .await
+        .map_err(|_| HandleClientError::Timeout(timeout))?;
+
+    match read {
+        Ok(packet) => Ok(packet),
+        Err(ReadChannelError::CodecError(err)) => Err(err.into()),
+        Err(err) => Err(err.into()),
+    }
+}
+
 async fn read_packets(
     address: SocketAddr,
-    mut reader: ReadChannel,
-    to_brain: &UnboundedSender<BrainMsg>,
+    mut reader: ReadChannel<impl AsyncRead + Unpin>,
+    writer: &WriteChannel,
+    to_brain: &Sender<BrainMsg>,
     conn: &ConnectionInfo,
+    epoch: Instant,
+    timeout: Duration,
 ) -> Result<!, HandleClientError> {
     loop {
-        let packet = reader.read_next().await?;
+        // a client that is alive answers our heartbeats, so it can't be quiet for long
+        let packet = tokio::time::timeout(timeout, reader.read_next())
+            .await
+            .map_err(|_| HandleClientError::Timeout(timeout))??;
+
         trace!("{address}: sent packet {packet:?}");
 
         match packet {
-            Packet::Request { kind, player } => {
-                to_brain.send(BrainMsg::Dispatch { kind, player })?;
+            Packet::HeartbeatAck { sent_at } => {
+                let latency = epoch
+                    .elapsed()
+                    .saturating_sub(Duration::from_millis(sent_at));
+                let name = conn.name.clone();
+                to_brain.send(BrainMsg::Latency { name, latency }).await?;
+            }
+            Packet::Request { kind, player, id } => {
+                let reply = id.map(|id| RequestReply::new(id, writer.clone()));
+                let msg = BrainMsg::Dispatch {
+                    kind,
+                    player,
+                    reply,
+                };
+
+                // every other packet waits for the brain to catch up, which stops us
+                // from reading from the client. requests are rejected instead, so that
+                // clients learn that they should try again later.
+                match to_brain.try_send(msg) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(msg)) => {
+                        let count = OVERLOADED_REQUESTS.fetch_add(1, Ordering::Relaxed) + 1;
+
+                        match msg {
+                            BrainMsg::Dispatch {
+                                reply: Some(reply), ..
+                            } => {
+                                warn!("{address}: the brain is overloaded, rejecting request ({count} overloaded requests so far)");
+                                reply.failed("the controller is overloaded".to_owned());
+                            }
+                            msg => {
+                                warn!("{address}: the brain is overloaded, dropping {msg:?} ({count} overloaded requests so far)");
+                            }
+                        }
+                    }
+                    Err(TrySendError::Closed(msg)) => return Err(SendError(msg).into()),
+                }
             }
             Packet::UpdateActive { active } if let Kind::Minigame { kind } = &conn.kind => {
                 let name = ServerName(conn.name.clone());
                 let msg = ClusterMsg::UpdateActive { name, active };
-                to_brain.send(BrainMsg::ClusterForward {
-                    minigame_kind: kind.clone(),
-                    msg,
-                })?;
+                to_brain
+                    .send(BrainMsg::ClusterForward {
+                        minigame_kind: kind.clone(),
+                        msg,
+                    })
+                    .await?;
             }
             Packet::Pong { timer } if let Kind::Minigame { kind } = &conn.kind => {
                 let name = ServerName(conn.name.clone());
                 let msg = ClusterMsg::ServerPong(timer, name);
-                to_brain.send(BrainMsg::ClusterForward {
-                    minigame_kind: kind.clone(),
-                    msg,
-                })?;
+                to_brain
+                    .send(BrainMsg::ClusterForward {
+                        minigame_kind: kind.clone(),
+                        msg,
+                    })
+                    .await?;
+            }
+            Packet::PlayerJoined { player } if matches!(conn.kind, Kind::Proxy) => {
+                let proxy = conn.name.clone();
+                to_brain
+                    .send(BrainMsg::PlayerJoined { proxy, player })
+                    .await?;
+            }
+            Packet::PlayerLeft { player } if matches!(conn.kind, Kind::Proxy) => {
+                let proxy = conn.name.clone();
+                to_brain
+                    .send(BrainMsg::PlayerLeft { proxy, player })
+                    .await?;
+            }
+            Packet::PlayerCount { players } if matches!(conn.kind, Kind::Lobby) => {
+                let name = conn.name.clone();
+                to_brain
+                    .send(BrainMsg::LobbyPlayerCount { name, players })
+                    .await?;
+            }
+            Packet::PlayerCount { players } if let Kind::Minigame { kind } = &conn.kind => {
+                let name = ServerName(conn.name.clone());
+                let msg = ClusterMsg::PlayerCount { name, players };
+                to_brain
+                    .send(BrainMsg::ClusterForward {
+                        minigame_kind: kind.clone(),
+                        msg,
+                    })
+                    .await?;
             }
             p => return Err(HandleClientError::SpuriousPacket(p)),
         };
     }
 }
+
+async fn send_heartbeats(writer: WriteChannel, epoch: Instant, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        let sent_at = epoch.elapsed().as_millis() as u64;
+        if let Err(err) = writer.send(&Packet::Heartbeat { sent_at }) {
+            // the reading side will notice that the connection is dead
+            trace!("couldn't send heartbeat: {err}");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Secret;
+    use controller_client::codec::{Codec, MessagePack};
+    use controller_client::frame::{read_frame, write_frame};
+    use tokio::io::DuplexStream;
+    use tokio::sync::mpsc::channel;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn config() -> Config {
+        Config {
+            heartbeat_timeout: TIMEOUT,
+            ..Config::default()
+        }
+    }
+
+    fn authentication(ip: &str) -> Packet {
+        Packet::Authentication {
+            name: "proxy".to_owned(),
+            kind: Kind::Proxy,
+            ip: ip.to_owned(),
+            version: PROTOCOL_VERSION,
+            features: Vec::new(),
+            token: None,
+        }
+    }
+
+    async fn send(client: &mut DuplexStream, packet: &Packet) {
+        let bytes = MessagePack.encode(packet).unwrap();
+        write_frame(client, &bytes, None).await.unwrap();
+    }
+
+    async fn recv(client: &mut DuplexStream) -> Packet {
+        let frame = read_frame(client, 1024).await.unwrap();
+        MessagePack.decode(&frame).unwrap()
+    }
+
+    async fn handshake(
+        config: Config,
+    ) -> (
+        DuplexStream,
+        tokio::task::JoinHandle<Result<!, HandleClientError>>,
+    ) {
+        let (client, server) = tokio::io::duplex(1024);
+        let (to_brain, _brain) = channel(8);
+        let address = "127.0.0.1:4000".parse().unwrap();
+
+        let task =
+            tokio::spawn(
+                async move { handle_client(to_brain, server, address, &config, None).await },
+            );
+
+        (client, task)
+    }
+
+    #[tokio::test]
+    async fn disconnects_clients_that_dont_authenticate() {
+        let (_client, task) = handshake(config()).await;
+
+        let result = task.await.unwrap();
+        assert!(matches!(result, Err(HandleClientError::Timeout(TIMEOUT))));
+    }
+
+    #[tokio::test]
+    async fn disconnects_clients_that_dont_answer_the_challenge() {
+        let config = Config {
+            secret: Some(Secret("secret".to_owned())),
+            ..config()
+        };
+
+        let (mut client, task) = handshake(config).await;
+        send(&mut client, &authentication("/127.0.0.1:25565")).await;
+        assert!(matches!(recv(&mut client).await, Packet::Challenge { .. }));
+
+        let result = task.await.unwrap();
+        assert!(matches!(result, Err(HandleClientError::Timeout(TIMEOUT))));
+    }
+
+    #[tokio::test]
+    async fn rejects_clients_with_an_invalid_address() {
+        let (mut client, task) = handshake(config()).await;
+        send(&mut client, &authentication("not an address")).await;
+
+        let reply = recv(&mut client).await;
+        assert!(matches!(reply, Packet::AuthenticationRejected { .. }));
+
+        let result = task.await.unwrap();
+        assert!(matches!(result, Err(HandleClientError::InvalidAddress(_))));
+    }
+}
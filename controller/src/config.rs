@@ -0,0 +1,292 @@
+use crate::auth::Secret;
+use crate::lobby_pool::PlacementPolicy;
+use crate::minigame_cluster::ClusterConfig;
+use log::warn;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Configuration for the controller. Every setting is read from an environment
+/// variable, falling back to a sensible default if it isn't set.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// `CONTROLLER_SECRET`: the secret shared between the controller and every
+    /// server, which servers must prove they know before they are trusted. It's
+    /// passed on to every spawned server. If it isn't set, any server is trusted.
+    pub secret: Option<Secret>,
+    /// `TLS_CERT`: the path to the PEM encoded certificate chain of the controller.
+    /// Connections to the controller use TLS if both this and `TLS_KEY` are set.
+    pub tls_cert: Option<PathBuf>,
+    /// `TLS_KEY`: the path to the PEM encoded private key of the controller.
+    pub tls_key: Option<PathBuf>,
+    /// `TLS_CLIENT_CA`: the path to the PEM encoded CA certificate that every client
+    /// certificate must be signed by. If it isn't set, clients don't need a certificate.
+    pub tls_client_ca: Option<PathBuf>,
+    /// `MAX_FRAME_SIZE`: the largest frame, in bytes, that a server may send to the
+    /// controller. Servers that send a larger frame are disconnected.
+    pub max_frame_size: u32,
+    /// `OUTBOUND_QUEUE_SIZE`: how many packets may wait to be written to a server.
+    /// Servers that fall further behind are disconnected.
+    pub outbound_queue_size: usize,
+    /// `COMPRESSION_THRESHOLD`: packets larger than this, in bytes, are compressed
+    /// for servers that support compression.
+    pub compression_threshold: usize,
+    /// `BRAIN_QUEUE_SIZE`: how many messages may wait to be handled by the brain.
+    /// Once it's full, requests are rejected and servers aren't read from until
+    /// the brain catches up.
+    pub brain_queue_size: usize,
+    /// `CLUSTER_QUEUE_SIZE`: how many messages may wait to be handled by each
+    /// minigame cluster.
+    pub cluster_queue_size: usize,
+    /// `SPAWN_TIMEOUT_SECS`: how long a spawned server has to connect to the
+    /// controller, before it is stopped and replaced.
+    pub spawn_timeout: Duration,
+    /// `HEARTBEAT_INTERVAL_SECS`: how often the controller sends a heartbeat to
+    /// every server.
+    pub heartbeat_interval: Duration,
+    /// `HEARTBEAT_TIMEOUT_SECS`: how long a server may not send anything before it
+    /// is considered dead, including while it authenticates. Should be a few times
+    /// the heartbeat interval.
+    pub heartbeat_timeout: Duration,
+    /// `LOBBY_PLACEMENT`: how players are placed into lobby servers, either
+    /// `least-loaded` or `fill-first`.
+    pub lobby_placement: PlacementPolicy,
+    /// `LOBBY_CAPACITY`: the amount of players a lobby server is meant to hold.
+    pub lobby_capacity: u32,
+    /// `MIN_LOBBIES`: the amount of lobby servers to always keep online.
+    pub min_lobbies: usize,
+    /// `MAX_LOBBIES`: the most lobby servers the autoscaler will keep online.
+    pub max_lobbies: usize,
+    /// `LOBBY_SCALE_UP_FILL`: once the average fill of the lobby servers (from
+    /// `0.0` to `1.0`) crosses this high-water mark, another lobby is spawned.
+    pub lobby_scale_up_fill: f32,
+    /// `LOBBY_SCALE_DOWN_FILL`: a lobby server with a fill below this low-water
+    /// mark for long enough is drained and stopped.
+    pub lobby_scale_down_fill: f32,
+    /// `LOBBY_SCALE_DOWN_AFTER_SECS`: how long a lobby server must sit below the
+    /// low-water mark before it is drained.
+    pub lobby_scale_down_after: Duration,
+    /// `DOCKER_NETWORK`: the docker network that spawned servers are connected to.
+    pub docker_network: String,
+    /// `LOBBY_IMAGE`: the docker image to spawn lobby servers from.
+    pub lobby_image: String,
+    /// `LIMBO_IMAGE`: the docker image to spawn the limbo server from. A limbo
+    /// server only needs to hold players, so the lobby image works just fine.
+    pub limbo_image: String,
+    /// `MINIGAME_IMAGE`: the docker image to spawn minigame servers from. See
+    /// [`PerKind`] for the format.
+    pub minigame_image: PerKind<String>,
+    /// `MINIGAME_WARM_POOL`: the amount of idle minigame servers to keep booted
+    /// for every minigame kind, so that players don't have to wait for a server
+    /// to start. See [`PerKind`] for the format.
+    pub minigame_warm_pool: PerKind<usize>,
+    /// `MINIGAME_IDLE_TIMEOUT_SECS`: how long a minigame server may be empty
+    /// before it is stopped. See [`PerKind`] for the format.
+    pub minigame_idle_timeout_secs: PerKind<u64>,
+    /// `MINIGAME_MIN_SERVERS`: the least amount of minigame servers to keep online
+    /// for every minigame kind. See [`PerKind`] for the format.
+    pub minigame_min_servers: PerKind<usize>,
+    /// `MINIGAME_MAX_SERVERS`: the most minigame servers that may be online for
+    /// every minigame kind, unlimited by default. Once a minigame kind is at its
+    /// maximum, players wait for a server to accept them instead of a new server
+    /// being spawned. See [`PerKind`] for the format.
+    pub minigame_max_servers: PerKind<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            max_frame_size: 1024 * 1024,
+            outbound_queue_size: 256,
+            compression_threshold: 1024,
+            brain_queue_size: 1024,
+            cluster_queue_size: 256,
+            spawn_timeout: Duration::from_secs(300),
+            heartbeat_interval: Duration::from_secs(5),
+            heartbeat_timeout: Duration::from_secs(15),
+            lobby_placement: PlacementPolicy::LeastLoaded,
+            lobby_capacity: 50,
+            min_lobbies: 1,
+            max_lobbies: 8,
+            lobby_scale_up_fill: 0.8,
+            lobby_scale_down_fill: 0.25,
+            lobby_scale_down_after: Duration::from_secs(300),
+            docker_network: "ems_network".to_owned(),
+            lobby_image: "ems-lobby".to_owned(),
+            limbo_image: "ems-lobby".to_owned(),
+            minigame_image: PerKind::new("ems-minigame".to_owned()),
+            minigame_warm_pool: PerKind::new(0),
+            minigame_idle_timeout_secs: PerKind::new(300),
+            minigame_min_servers: PerKind::new(0),
+            minigame_max_servers: PerKind::new(usize::MAX),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let default = Config::default();
+
+        Self {
+            secret: std::env::var("CONTROLLER_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty())
+                .map(Secret),
+            tls_cert: env_path("TLS_CERT"),
+            tls_key: env_path("TLS_KEY"),
+            tls_client_ca: env_path("TLS_CLIENT_CA"),
+            max_frame_size: env_or("MAX_FRAME_SIZE", default.max_frame_size),
+            outbound_queue_size: env_or("OUTBOUND_QUEUE_SIZE", default.outbound_queue_size),
+            compression_threshold: env_or("COMPRESSION_THRESHOLD", default.compression_threshold),
+            brain_queue_size: env_or("BRAIN_QUEUE_SIZE", default.brain_queue_size),
+            cluster_queue_size: env_or("CLUSTER_QUEUE_SIZE", default.cluster_queue_size),
+            spawn_timeout: Duration::from_secs(env_or(
+                "SPAWN_TIMEOUT_SECS",
+                default.spawn_timeout.as_secs(),
+            )),
+            heartbeat_interval: Duration::from_secs(env_or(
+                "HEARTBEAT_INTERVAL_SECS",
+                default.heartbeat_interval.as_secs(),
+            )),
+            heartbeat_timeout: Duration::from_secs(env_or(
+                "HEARTBEAT_TIMEOUT_SECS",
+                default.heartbeat_timeout.as_secs(),
+            )),
+            lobby_placement: env_or("LOBBY_PLACEMENT", default.lobby_placement),
+            lobby_capacity: env_or("LOBBY_CAPACITY", default.lobby_capacity),
+            min_lobbies: env_or("MIN_LOBBIES", default.min_lobbies),
+            max_lobbies: env_or("MAX_LOBBIES", default.max_lobbies),
+            lobby_scale_up_fill: env_or("LOBBY_SCALE_UP_FILL", default.lobby_scale_up_fill),
+            lobby_scale_down_fill: env_or("LOBBY_SCALE_DOWN_FILL", default.lobby_scale_down_fill),
+            lobby_scale_down_after: Duration::from_secs(env_or(
+                "LOBBY_SCALE_DOWN_AFTER_SECS",
+                default.lobby_scale_down_after.as_secs(),
+            )),
+            docker_network: env_or("DOCKER_NETWORK", default.docker_network),
+            lobby_image: env_or("LOBBY_IMAGE", default.lobby_image),
+            limbo_image: env_or("LIMBO_IMAGE", default.limbo_image),
+            minigame_image: env_per_kind("MINIGAME_IMAGE", default.minigame_image),
+            minigame_warm_pool: env_per_kind("MINIGAME_WARM_POOL", default.minigame_warm_pool),
+            minigame_idle_timeout_secs: env_per_kind(
+                "MINIGAME_IDLE_TIMEOUT_SECS",
+                default.minigame_idle_timeout_secs,
+            ),
+            minigame_min_servers: env_per_kind(
+                "MINIGAME_MIN_SERVERS",
+                default.minigame_min_servers,
+            ),
+            minigame_max_servers: env_per_kind(
+                "MINIGAME_MAX_SERVERS",
+                default.minigame_max_servers,
+            ),
+        }
+    }
+
+    /// The settings for the minigame cluster of a specific minigame kind.
+    pub fn cluster_config(&self, kind: &str) -> ClusterConfig {
+        ClusterConfig {
+            warm_pool: self.minigame_warm_pool.get(kind),
+            idle_timeout: Duration::from_secs(self.minigame_idle_timeout_secs.get(kind)),
+            min_servers: self.minigame_min_servers.get(kind),
+            max_servers: self.minigame_max_servers.get(kind),
+            queue_size: self.cluster_queue_size,
+        }
+    }
+}
+
+/// A setting that can be different for every minigame kind. In an environment
+/// variable, it's written as a comma separated list like `1,skywars=3`, where
+/// the value without a minigame kind applies to every other minigame kind.
+#[derive(Debug, Clone)]
+pub struct PerKind<T> {
+    default: T,
+    kinds: HashMap<String, T>,
+}
+
+impl<T: Clone> PerKind<T> {
+    pub fn new(default: T) -> Self {
+        Self {
+            default,
+            kinds: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, kind: &str) -> T {
+        self.kinds.get(kind).unwrap_or(&self.default).clone()
+    }
+
+    /// Every minigame kind that has been configured explicitly.
+    pub fn kinds(&self) -> impl Iterator<Item = &String> {
+        self.kinds.keys()
+    }
+}
+
+fn env_path(key: &str) -> Option<PathBuf> {
+    std::env::var_os(key)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+fn env_or<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let Ok(value) = std::env::var(key) else {
+        return default;
+    };
+
+    match value.parse() {
+        Ok(value) => value,
+        Err(err) => {
+            warn!("config: couldn't parse {key}={value:?} ({err}), using the default");
+            default
+        }
+    }
+}
+
+fn env_per_kind<T>(key: &str, default: PerKind<T>) -> PerKind<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let Ok(value) = std::env::var(key) else {
+        return default;
+    };
+
+    let mut default_value = None;
+    let mut kinds = HashMap::new();
+
+    for part in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (kind, part) = match part.split_once('=') {
+            Some((kind, part)) => (Some(kind.trim()), part.trim()),
+            None => (None, part),
+        };
+
+        let part = match part.parse() {
+            Ok(part) => part,
+            Err(err) => {
+                warn!("config: couldn't parse {key}={value:?} ({err}), using the default");
+                return default;
+            }
+        };
+
+        match kind {
+            Some(kind) => {
+                kinds.insert(kind.to_owned(), part);
+            }
+            None => default_value = Some(part),
+        }
+    }
+
+    PerKind {
+        default: default_value.unwrap_or(default.default),
+        kinds,
+    }
+}
@@ -0,0 +1,192 @@
+use crate::backend::ServerBackend;
+use crate::brain::{start_brain, BrainMsg};
+use crate::client::start_client_listener;
+use crate::config::Config;
+use crate::docker::DockerBackend;
+use crate::http::{start_web_server, GlobalComputerMap, QueueMetrics, WebServer, WebServerError};
+use crate::tls::{self, TlsError};
+use log::{info, warn};
+use std::net::SocketAddr;
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// The address servers connect to by default.
+pub const DEFAULT_LISTEN_ADDRESS: ([u8; 4], u16) = ([0, 0, 0, 0], 25550);
+
+/// The address the web server listens on by default.
+pub const DEFAULT_HTTP_ADDRESS: ([u8; 4], u16) = ([0, 0, 0, 0], 25580);
+
+/// Runs a controller. Use [`Controller::builder`] to start one.
+pub struct Controller;
+
+impl Controller {
+    pub fn builder() -> ControllerBuilder<DockerBackend> {
+        ControllerBuilder {
+            listen_address: None,
+            http_address: None,
+            backend: Box::new(|config| Ok(DockerBackend::connect(config)?)),
+            config: None,
+        }
+    }
+}
+
+/// Sets up a controller, see [`Controller::builder`]. Everything that isn't set
+/// explicitly uses the defaults of the standalone controller.
+pub struct ControllerBuilder<B> {
+    listen_address: Option<SocketAddr>,
+    http_address: Option<SocketAddr>,
+    backend: MakeBackend<B>,
+    config: Option<Config>,
+}
+
+/// Creates the backend of a controller once its config is known.
+type MakeBackend<B> = Box<dyn FnOnce(&Config) -> Result<B, StartError> + Send>;
+
+#[derive(Error, Debug)]
+pub enum StartError {
+    #[error("couldn't listen on {0}: {1}")]
+    Listen(SocketAddr, std::io::Error),
+    #[error("couldn't set up TLS: {0}")]
+    Tls(#[from] TlsError),
+    #[error("couldn't connect to docker: {0}")]
+    Docker(#[from] bollard::errors::Error),
+    #[error(transparent)]
+    WebServer(#[from] WebServerError),
+}
+
+impl<B: ServerBackend> ControllerBuilder<B> {
+    /// The address that servers connect to, `0.0.0.0:25550` by default. Use port `0`
+    /// to pick any free port, see [`ControllerHandle::local_addr`].
+    pub fn listen_address(mut self, addr: SocketAddr) -> Self {
+        self.listen_address = Some(addr);
+        self
+    }
+
+    /// The address of the web server, `0.0.0.0:25580` by default. Use port `0` to
+    /// pick any free port, see [`ControllerHandle::http_addr`].
+    pub fn http_address(mut self, addr: SocketAddr) -> Self {
+        self.http_address = Some(addr);
+        self
+    }
+
+    /// What runs the servers that the controller spawns. By default, they're run
+    /// as containers by the local docker daemon.
+    pub fn backend<C: ServerBackend>(self, backend: C) -> ControllerBuilder<C> {
+        ControllerBuilder {
+            listen_address: self.listen_address,
+            http_address: self.http_address,
+            backend: Box::new(|_| Ok(backend)),
+            config: self.config,
+        }
+    }
+
+    /// The settings of the controller, [`Config::default`] by default. Use
+    /// [`Config::from_env`] to read them from environment variables instead.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Starts the controller in the background. It runs until it is shut down
+    /// with [`ControllerHandle::shutdown`].
+    pub async fn start(self) -> Result<ControllerHandle, StartError> {
+        let config = self.config.unwrap_or_default();
+        info!("using {config:?}");
+
+        let acceptor = tls::acceptor(&config)?;
+
+        let backend = (self.backend)(&config)?;
+
+        let addr = self.listen_address.unwrap_or(DEFAULT_LISTEN_ADDRESS.into());
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|err| StartError::Listen(addr, err))?;
+        let local_addr = listener
+            .local_addr()
+            .map_err(|err| StartError::Listen(addr, err))?;
+        info!("listening on {local_addr}");
+
+        // List of computers and their online/offline statuses for observability into the servers via the dashboard
+        let computers = GlobalComputerMap::default();
+
+        // The depth of the internal queues, to see whether the controller keeps up
+        let queues = QueueMetrics::default();
+
+        let http_addr = self.http_address.unwrap_or(DEFAULT_HTTP_ADDRESS.into());
+        let web_server = start_web_server(http_addr, computers.clone(), queues.clone())?;
+
+        let (brain_sender, brain) = start_brain(config.clone(), backend, computers, queues);
+
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+        let listener = start_client_listener(
+            listener,
+            acceptor,
+            brain_sender.clone(),
+            config,
+            shutdown_receiver,
+        );
+        let listener = tokio::task::spawn(listener);
+
+        Ok(ControllerHandle {
+            local_addr,
+            web_server,
+            shutdown,
+            listener,
+            brain_sender,
+            brain,
+        })
+    }
+}
+
+/// A running controller. Dropping the handle leaves the controller running in the
+/// background, use [`ControllerHandle::shutdown`] to stop it.
+pub struct ControllerHandle {
+    local_addr: SocketAddr,
+    web_server: WebServer,
+    shutdown: watch::Sender<bool>,
+    listener: JoinHandle<()>,
+    brain_sender: Sender<BrainMsg>,
+    brain: JoinHandle<()>,
+}
+
+impl ControllerHandle {
+    /// The address that servers connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The address of the web server.
+    pub fn http_addr(&self) -> SocketAddr {
+        self.web_server.local_addr()
+    }
+
+    /// Stops the controller. Servers can't connect anymore, every connection is
+    /// closed once the packets queued for it have been sent, and every spawned
+    /// server is stopped.
+    pub async fn shutdown(self) {
+        info!("shutting down");
+
+        // sending only fails if the listener has already stopped on its own
+        let _ = self.shutdown.send(true);
+        if let Err(err) = self.listener.await {
+            warn!("client listener failed: {err}");
+        }
+
+        // the brain holds on to the connections, which are closed once it stops.
+        // sending only fails if it has already stopped, which it has logged
+        let _ = self.brain_sender.send(BrainMsg::Shutdown).await;
+        if let Err(err) = self.brain.await {
+            warn!("brain failed: {err}");
+        }
+
+        let web_server = self.web_server;
+        if let Err(err) = tokio::task::spawn_blocking(|| web_server.stop()).await {
+            warn!("web server failed to stop: {err}");
+        }
+
+        info!("shut down");
+    }
+}
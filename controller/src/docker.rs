@@ -0,0 +1,274 @@
+use crate::auth::Secret;
+use crate::backend::{ServerBackend, ServerInfo};
+use crate::config::{Config, PerKind};
+use crate::transport::Kind;
+use bollard::container::{
+    Config as ContainerConfig, ListContainersOptions, RemoveContainerOptions,
+};
+use bollard::errors::Error;
+use bollard::network::ConnectNetworkOptions;
+use bollard::service::EndpointSettings;
+use bollard::Docker;
+use log::{info, trace, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use thiserror::Error;
+
+/// The label holding the name of the server that a container runs. Containers are
+/// labelled, so that they're found even if an earlier controller spawned them.
+const SERVER_LABEL: &str = "controller.server";
+
+/// The label holding the [`Kind`] of the server that a container runs, as JSON.
+const KIND_LABEL: &str = "controller.kind";
+
+/// The label holding the network a container was spawned for, so that controllers
+/// with their own network don't touch each other's containers.
+const NETWORK_LABEL: &str = "controller.network";
+
+/// Spawns servers as docker containers, and stops them again.
+pub struct DockerBackend {
+    docker: Docker,
+    /// The network every container is connected to, so that they can reach the
+    /// controller and the proxies can reach them.
+    network: String,
+    lobby_image: String,
+    limbo_image: String,
+    minigame_image: PerKind<String>,
+    secret: Option<Secret>,
+    /// Whether servers must connect to the controller with TLS.
+    tls: bool,
+    /// Maps the name of every spawned server to its container.
+    containers: HashMap<String, Container>,
+}
+
+#[derive(Error, Debug)]
+pub enum DockerError {
+    #[error("Docker error: {0}")]
+    Docker(#[from] Error),
+    #[error("Proxies can't be spawned")]
+    CantSpawnProxy,
+}
+
+struct Container {
+    id: String,
+    kind: Kind,
+}
+
+impl DockerBackend {
+    /// Connects to the local docker daemon, which is found through `DOCKER_HOST`
+    /// or the default socket.
+    pub fn connect(config: &Config) -> Result<Self, Error> {
+        let docker = Docker::connect_with_local_defaults()?;
+        Ok(Self::new(docker, config))
+    }
+
+    pub fn new(docker: Docker, config: &Config) -> Self {
+        Self {
+            docker,
+            network: config.docker_network.clone(),
+            lobby_image: config.lobby_image.clone(),
+            limbo_image: config.limbo_image.clone(),
+            minigame_image: config.minigame_image.clone(),
+            secret: config.secret.clone(),
+            tls: config.tls_cert.is_some(),
+            containers: HashMap::new(),
+        }
+    }
+}
+
+impl ServerBackend for DockerBackend {
+    type Error = DockerError;
+
+    async fn spawn(
+        &mut self,
+        server_name: String,
+        kind: Kind,
+        token: String,
+    ) -> Result<(), DockerError> {
+        let mut env = Vec::new();
+
+        env.push("CONTROLLER_IP=controller".to_owned());
+        env.push(format!("SERVER_NAME={server_name}"));
+        env.push(format!("SPAWN_TOKEN={token}"));
+
+        if let Some(Secret(secret)) = &self.secret {
+            env.push(format!("CONTROLLER_SECRET={secret}"));
+        }
+
+        if self.tls {
+            env.push("CONTROLLER_TLS=true".to_owned());
+        }
+
+        let image = match &kind {
+            // proxies are run by whoever runs the network, not by the controller
+            Kind::Proxy => return Err(DockerError::CantSpawnProxy),
+            Kind::Limbo => {
+                env.push("SERVER_KIND=Limbo".to_owned());
+                self.limbo_image.clone()
+            }
+            Kind::Lobby => {
+                env.push("SERVER_KIND=Lobby".to_owned());
+                self.lobby_image.clone()
+            }
+            Kind::Minigame { kind } => {
+                env.push("SERVER_KIND=Minigame".to_owned());
+                env.push(format!("MINIGAME_KIND={kind}"));
+                self.minigame_image.get(kind)
+            }
+        };
+
+        let labels = HashMap::from([
+            (SERVER_LABEL.to_owned(), server_name.clone()),
+            (
+                KIND_LABEL.to_owned(),
+                serde_json::to_string(&kind).expect("kinds can be serialized"),
+            ),
+            (NETWORK_LABEL.to_owned(), self.network.clone()),
+        ]);
+
+        let opts = ContainerConfig {
+            env: Some(env),
+            image: Some(image),
+            labels: Some(labels),
+            ..Default::default()
+        };
+
+        trace!("docker: spawning child...");
+        let container = self
+            .docker
+            .create_container::<String, _>(None, opts)
+            .await?;
+
+        for warning in container.warnings {
+            trace!("docker: warning {warning}");
+        }
+
+        let id = container.id;
+        info!("docker: spawned server {id}");
+
+        let started = async {
+            self.docker
+                .connect_network(
+                    &self.network,
+                    ConnectNetworkOptions {
+                        container: &id,
+                        endpoint_config: EndpointSettings {
+                            ..Default::default()
+                        },
+                    },
+                )
+                .await?;
+
+            info!("docker: connected new server to network!");
+
+            self.docker.start_container::<String>(&id, None).await
+        };
+
+        // the server can't run, so don't leave its container behind
+        if let Err(err) = started.await {
+            let opts = RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            };
+
+            if let Err(err) = self.docker.remove_container(&id, Some(opts)).await {
+                warn!("docker: couldn't remove container {id} of server {server_name}: {err}");
+            }
+
+            return Err(err.into());
+        }
+
+        self.containers.insert(server_name, Container { id, kind });
+
+        Ok(())
+    }
+
+    /// Stops and removes the container of a server that was spawned earlier.
+    fn stop(
+        &mut self,
+        server_name: &str,
+    ) -> impl Future<Output = Result<(), DockerError>> + Send + 'static {
+        let container = self.containers.remove(server_name);
+        let docker = self.docker.clone();
+        let server_name = server_name.to_owned();
+
+        async move {
+            let Some(Container { id, .. }) = container else {
+                trace!("docker: no container known for server {server_name}, not stopping it");
+                return Ok(());
+            };
+
+            trace!("docker: stopping server {server_name} ({id})");
+
+            // a container that isn't running (anymore) can't be stopped, but it
+            // still has to be removed
+            if let Err(err) = docker.stop_container(&id, None).await {
+                trace!("docker: couldn't stop server {server_name} ({id}): {err}");
+            }
+
+            let opts = RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            };
+            docker.remove_container(&id, Some(opts)).await?;
+
+            info!("docker: stopped server {server_name}");
+            Ok(())
+        }
+    }
+
+    /// Lists the servers of every container on our network, including the ones that
+    /// an earlier controller spawned. Those are adopted, so that they can be stopped.
+    async fn list(&mut self) -> Result<Vec<String>, DockerError> {
+        let opts = ListContainersOptions {
+            all: true,
+            filters: HashMap::from([(
+                "label".to_owned(),
+                vec![format!("{NETWORK_LABEL}={}", self.network)],
+            )]),
+            ..Default::default()
+        };
+
+        let containers = self.docker.list_containers(Some(opts)).await?;
+
+        for container in containers {
+            let (Some(id), Some(labels)) = (container.id, container.labels) else {
+                continue;
+            };
+
+            let (Some(name), Some(kind)) = (labels.get(SERVER_LABEL), labels.get(KIND_LABEL))
+            else {
+                warn!("docker: container {id} isn't labelled with its server, ignoring it");
+                continue;
+            };
+
+            let Ok(kind) = serde_json::from_str(kind) else {
+                warn!("docker: container {id} has an unknown kind {kind:?}, ignoring it");
+                continue;
+            };
+
+            self.containers.entry(name.clone()).or_insert_with(|| {
+                info!("docker: found container {id} of server {name}");
+                Container { id, kind }
+            });
+        }
+
+        Ok(self.containers.keys().cloned().collect())
+    }
+
+    async fn inspect(&mut self, server_name: &str) -> Result<Option<ServerInfo>, DockerError> {
+        let Some(Container { id, kind }) = self.containers.get(server_name) else {
+            return Ok(None);
+        };
+
+        let container = self.docker.inspect_container(id, None).await?;
+        let running = container.state.and_then(|state| state.running);
+
+        Ok(Some(ServerInfo {
+            name: server_name.to_owned(),
+            kind: kind.clone(),
+            id: id.clone(),
+            running: running.unwrap_or(false),
+        }))
+    }
+}
@@ -1,41 +1,118 @@
 use std::{
     collections::BTreeMap,
+    net::SocketAddr,
     sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
 };
 
+use crate::client;
 use rouille::Response;
+use thiserror::Error;
+use tokio::sync::mpsc::{Sender, WeakSender};
 
-/// Starts the web server, which reports the currently known servers and their statuses.
-pub fn start_web_server(computers: GlobalComputerMap) {
-    tokio::task::spawn(async {
-        log::info!("web server starting on :25580");
-
-        rouille::start_server("0.0.0.0:25580", move |_| {
-            let mut response = String::with_capacity(1024);
-
-            for (computer, status) in computers.list_statuses() {
-                response.push_str(&computer);
-                response.push(',');
-                response.push_str(match status {
-                    ComputerStatus::Starting => "starting",
-                    ComputerStatus::Online => "online",
-                    // Could be made more type safe but w/e.
-                    ComputerStatus::Offline => {
-                        unreachable!("list_statuses will never return Offline")
-                    }
-                });
-                response.push('\n');
-            }
+/// Starts the web server on `addr`, which reports the currently known servers and their
+/// statuses. The depth of every internal queue is reported under `/queues`, the
+/// amount of peers that were disconnected for being malformed under `/malformed`,
+/// and the amount of requests that were turned away for the controller being
+/// overloaded under `/overloaded`.
+///
+/// The web server runs on its own threads until it is stopped with [`WebServer::stop`].
+pub fn start_web_server(
+    addr: SocketAddr,
+    computers: GlobalComputerMap,
+    queues: QueueMetrics,
+) -> Result<WebServer, WebServerError> {
+    let server = rouille::Server::new(addr, move |request| {
+        Response::text(render(&request.url(), &computers, &queues))
+    })
+    .map_err(WebServerError)?;
+
+    let addr = server.server_addr();
+    log::info!("web server listening on {addr}");
+
+    let (thread, stop) = server.stoppable();
+    Ok(WebServer { addr, thread, stop })
+}
 
-            Response::text(response)
+/// Renders the page at `url`, see [`start_web_server`].
+fn render(url: &str, computers: &GlobalComputerMap, queues: &QueueMetrics) -> String {
+    let mut response = String::with_capacity(1024);
+
+    if url == "/queues" {
+        for (queue, depth, capacity) in queues.list_depths() {
+            response.push_str(&format!("{queue},{depth},{capacity}\n"));
+        }
+
+        return response;
+    }
+
+    if url == "/malformed" {
+        return format!("{}\n", client::malformed_peers());
+    }
+
+    if url == "/overloaded" {
+        return format!("{}\n", client::overloaded_requests());
+    }
+
+    for (computer, status, latency) in computers.list_statuses() {
+        response.push_str(&computer);
+        response.push(',');
+        response.push_str(match status {
+            ComputerStatus::Starting => "starting",
+            ComputerStatus::Online => "online",
+            // Could be made more type safe but w/e.
+            ComputerStatus::Offline => {
+                unreachable!("list_statuses will never return Offline")
+            }
         });
-    });
+        if let Some(latency) = latency {
+            response.push(',');
+            response.push_str(&latency.as_millis().to_string());
+        }
+        response.push('\n');
+    }
+
+    response
+}
+
+#[derive(Error, Debug)]
+#[error("couldn't start the web server: {0}")]
+pub struct WebServerError(Box<dyn std::error::Error + Send + Sync>);
+
+/// A running web server, see [`start_web_server`].
+pub struct WebServer {
+    addr: SocketAddr,
+    thread: JoinHandle<()>,
+    stop: std::sync::mpsc::Sender<()>,
+}
+
+impl WebServer {
+    /// The address that the web server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops the web server, and waits for the requests that are being handled. This
+    /// blocks the current thread.
+    pub fn stop(self) {
+        // the server is only gone already if its thread panicked, which was logged
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct GlobalComputerMap {
     // BTreeMap for stable order
-    data: Arc<Mutex<BTreeMap<String, ComputerStatus>>>,
+    data: Arc<Mutex<BTreeMap<String, Computer>>>,
+}
+
+#[derive(Clone, Copy)]
+struct Computer {
+    status: ComputerStatus,
+    /// The round-trip latency of the last heartbeat, once one has been answered.
+    latency: Option<Duration>,
 }
 
 #[derive(Clone, Copy)]
@@ -55,13 +132,32 @@ impl GlobalComputerMap {
         match status {
             ComputerStatus::Offline => {
                 let computer_name = computer_name.to_string();
-                data.remove(&computer_name)
+                data.remove(&computer_name);
+            }
+            status => {
+                let computer = data.entry(computer_name.to_string()).or_insert(Computer {
+                    status,
+                    latency: None,
+                });
+                computer.status = status;
             }
-            status => data.insert(computer_name.to_string(), status),
         };
     }
 
-    pub fn list_statuses(&self) -> Vec<(String, ComputerStatus)> {
+    /// Records the round-trip latency of a computer. Computers that aren't known
+    /// (anymore) are ignored.
+    pub fn set_latency(&self, computer_name: &str, latency: Duration) {
+        let mut data = match self.data.lock() {
+            Ok(guard) => guard,
+            Err(err) => err.into_inner(),
+        };
+
+        if let Some(computer) = data.get_mut(computer_name) {
+            computer.latency = Some(latency);
+        }
+    }
+
+    pub fn list_statuses(&self) -> Vec<(String, ComputerStatus, Option<Duration>)> {
         let data_guard = match self.data.lock() {
             Ok(guard) => guard,
             Err(err) => err.into_inner(),
@@ -69,6 +165,112 @@ impl GlobalComputerMap {
         let data = data_guard.clone();
         drop(data_guard);
 
-        data.into_iter().collect()
+        data.into_iter()
+            .map(|(name, computer)| (name, computer.status, computer.latency))
+            .collect()
+    }
+}
+
+/// Keeps track of the internal queues of the controller, so that their depth can be
+/// reported. A queue is only reported for as long as it is open.
+#[derive(Clone, Default)]
+pub struct QueueMetrics {
+    // BTreeMap for stable order
+    queues: Arc<Mutex<BTreeMap<String, Box<dyn QueueDepth>>>>,
+}
+
+/// A queue whose depth can be measured, regardless of what it carries.
+trait QueueDepth: Send {
+    /// Returns the amount of queued messages and the capacity of the queue, or
+    /// `None` if the queue has been closed.
+    fn depth(&self) -> Option<(usize, usize)>;
+}
+
+impl<T: Send> QueueDepth for WeakSender<T> {
+    fn depth(&self) -> Option<(usize, usize)> {
+        let sender = self.upgrade()?;
+        let capacity = sender.max_capacity();
+        Some((capacity - sender.capacity(), capacity))
+    }
+}
+
+impl QueueMetrics {
+    /// Starts reporting the depth of the queue that `sender` sends to under `name`.
+    pub fn register<T: Send + 'static, S: ToString>(&self, name: S, sender: &Sender<T>) {
+        let mut queues = match self.queues.lock() {
+            Ok(guard) => guard,
+            Err(err) => err.into_inner(),
+        };
+
+        queues.insert(name.to_string(), Box::new(sender.downgrade()));
+    }
+
+    /// Lists the name, depth and capacity of every open queue, and forgets about
+    /// the queues that have been closed.
+    pub fn list_depths(&self) -> Vec<(String, usize, usize)> {
+        let mut queues = match self.queues.lock() {
+            Ok(guard) => guard,
+            Err(err) => err.into_inner(),
+        };
+
+        let mut depths = Vec::with_capacity(queues.len());
+        queues.retain(|name, queue| match queue.depth() {
+            Some((depth, capacity)) => {
+                depths.push((name.clone(), depth, capacity));
+                true
+            }
+            None => false,
+        });
+
+        depths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_malformed_peers() {
+        let computers = GlobalComputerMap::default();
+        let queues = QueueMetrics::default();
+
+        let count = client::count_malformed_peer();
+        let page = render("/malformed", &computers, &queues);
+
+        // other tests may count malformed peers at the same time
+        let reported: usize = page.trim_end().parse().unwrap();
+        assert!(reported >= count);
+        assert!(page.ends_with('\n'));
+    }
+
+    #[test]
+    fn reports_overloaded_requests() {
+        let computers = GlobalComputerMap::default();
+        let queues = QueueMetrics::default();
+
+        let page = render("/overloaded", &computers, &queues);
+        let reported: usize = page.trim_end().parse().unwrap();
+        assert_eq!(reported, client::overloaded_requests());
+    }
+
+    #[test]
+    fn reports_statuses_and_queues() {
+        let computers = GlobalComputerMap::default();
+        computers.set_status("lobby-0", ComputerStatus::Online);
+        computers.set_latency("lobby-0", Duration::from_millis(12));
+        computers.set_status("lobby-1", ComputerStatus::Starting);
+        computers.set_status("lobby-2", ComputerStatus::Offline);
+
+        let queues = QueueMetrics::default();
+        let (sender, _receiver) = tokio::sync::mpsc::channel::<()>(4);
+        sender.try_send(()).unwrap();
+        queues.register("brain", &sender);
+
+        let page = render("/", &computers, &queues);
+        assert_eq!(page, "lobby-0,online,12\nlobby-1,starting\n");
+
+        let page = render("/queues", &computers, &queues);
+        assert_eq!(page, "brain,1,4\n");
     }
 }
@@ -0,0 +1,61 @@
+#![feature(try_blocks)]
+#![feature(once_cell)]
+#![feature(never_type)]
+#![feature(if_let_guard)]
+
+/// The HTTP module contains everything necessary for the HTTP API of the controller.
+/// The HTTP API is used by the Dashboard, to visualize the currently online and starting servers.
+pub mod http;
+
+/// The Brain is the brain of the controller. It handles the logic for what to do
+/// when new connections connect to it, juggling requests for packets, etc.
+pub mod brain;
+use brain::BrainMsg;
+
+/// The Transport module contains the low-level primitives for the underlying connection
+/// between the controller and servers. It contains primitives to wrap around raw TCP
+/// connections, and turns them into exchanges [`transport::Packet`]s
+pub mod transport;
+
+/// The schema module describes the protocol in a machine-readable way, so that
+/// client libraries can be generated and checked against it.
+pub mod schema;
+
+/// A minigame cluster is a grouping of minigame servers. These are necessary to
+/// facilitate filling in queued players into a running instance, as we must figure
+/// out which minigame server is
+pub mod minigame_cluster;
+use minigame_cluster::ClusterMsg;
+
+/// The auth module signs and verifies the challenges that clients must answer
+/// with the shared secret before they are trusted.
+pub mod auth;
+
+/// The TLS module sets up TLS for connections to the controller, and figures out
+/// which kinds of servers a client certificate allows.
+pub mod tls;
+
+/// The client module handles incoming connections as clients. It facilitates
+/// basic authentication and talks to the brain.
+pub mod client;
+
+/// The lobby pool keeps track of every lobby server, and decides which lobby
+/// server players are placed into.
+pub mod lobby_pool;
+
+/// The config module contains the settings of the controller, which are read
+/// from environment variables.
+pub mod config;
+
+/// The backend module describes how the servers that the controller spawns are
+/// run, see [`backend::ServerBackend`].
+pub mod backend;
+
+/// The docker module runs the servers that the controller spawns as docker
+/// containers.
+pub mod docker;
+
+/// The controller module starts every part of the controller, and stops them again.
+/// See [`Controller::builder`].
+pub mod controller;
+pub use controller::{Controller, ControllerBuilder, ControllerHandle, StartError};
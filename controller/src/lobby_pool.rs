@@ -0,0 +1,519 @@
+use crate::config::Config;
+use crate::transport::{RequestReply, WriteChannel};
+use derive_more::Display;
+use log::{info, trace, warn};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The lobby that players should be placed into is linked one priority above
+/// every other lobby, so that proxies forward newly joining players to it.
+pub const PREFERRED_LOBBY_PRIORITY: u16 = 3;
+
+/// Decides which lobby server a player is placed into.
+#[derive(Debug, Clone, Copy, Display)]
+pub enum PlacementPolicy {
+    /// Place players into the lobby with the least amount of players.
+    #[display(fmt = "least-loaded")]
+    LeastLoaded,
+    /// Place players into the oldest lobby that isn't full yet, so that the
+    /// newer lobbies stay as empty as possible.
+    #[display(fmt = "fill-first")]
+    FillFirst,
+}
+
+impl FromStr for PlacementPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "least-loaded" => Ok(PlacementPolicy::LeastLoaded),
+            "fill-first" => Ok(PlacementPolicy::FillFirst),
+            s => Err(format!("unknown placement policy {s}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LobbyServer {
+    pub name: String,
+    pub players: u32,
+    /// A draining lobby doesn't get any new players, and is stopped once it's empty.
+    pub draining: bool,
+    /// Since when the lobby has been below the low-water mark, if it is.
+    below_low_water_since: Option<Instant>,
+    // Prevent the writer from getting dropped, and thus the connection stays alive
+    _writer: WriteChannel,
+}
+
+impl LobbyServer {
+    fn fill(&self, capacity: u32) -> f32 {
+        self.players as f32 / capacity.max(1) as f32
+    }
+}
+
+/// What the lobby autoscaler decided to do. See [`LobbyPool::autoscale`].
+#[derive(Debug, Default)]
+pub struct Autoscale {
+    /// Whether a new lobby server should be spawned. It has already been recorded
+    /// as starting.
+    pub spawn: bool,
+    /// Lobby servers that have been drained, and should be stopped. They have
+    /// already been removed from the pool.
+    pub stop: Vec<String>,
+}
+
+/// Keeps track of every online lobby server, how many players are on each of them,
+/// and the players that are waiting on a lobby server to come online.
+#[derive(Debug)]
+pub struct LobbyPool {
+    policy: PlacementPolicy,
+    capacity: u32,
+    min_lobbies: usize,
+    max_lobbies: usize,
+    scale_up_fill: f32,
+    scale_down_fill: f32,
+    scale_down_after: Duration,
+    /// Every online lobby server, in the order they connected in.
+    lobbies: Vec<LobbyServer>,
+    /// The amount of lobby servers that have been spawned but haven't connected yet.
+    starting: usize,
+    /// Requests for a lobby that were made while no lobby server is online: the
+    /// player that wants to go there, if any, along with the reply to the request.
+    queue: Vec<(Option<String>, Option<RequestReply>)>,
+    /// The lobby that proxies currently forward new players to.
+    preferred: Option<String>,
+}
+
+impl LobbyPool {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            policy: config.lobby_placement,
+            capacity: config.lobby_capacity,
+            min_lobbies: config.min_lobbies,
+            max_lobbies: config.max_lobbies,
+            scale_up_fill: config.lobby_scale_up_fill,
+            scale_down_fill: config.lobby_scale_down_fill,
+            scale_down_after: config.lobby_scale_down_after,
+            lobbies: Vec::new(),
+            starting: 0,
+            queue: Vec::new(),
+            preferred: None,
+        }
+    }
+
+    pub fn push(&mut self, name: String, writer: WriteChannel) {
+        self.starting = self.starting.saturating_sub(1);
+
+        info!("lobby pool: adding lobby {name}");
+        self.lobbies.push(LobbyServer {
+            name,
+            players: 0,
+            draining: false,
+            below_low_water_since: None,
+            _writer: writer,
+        });
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        let Some(position) = self.lobbies.iter().position(|l| l.name == name) else {
+            return false;
+        };
+
+        info!("lobby pool: removing lobby {name}");
+        self.lobbies.remove(position);
+
+        if self.preferred.as_deref() == Some(name) {
+            self.preferred = None;
+        }
+
+        true
+    }
+
+    pub fn set_players(&mut self, name: &str, players: u32) {
+        match self.lobbies.iter_mut().find(|l| l.name == name) {
+            Some(lobby) => lobby.players = players,
+            None => warn!("lobby pool: unable to find lobby {name}. current lobbies: {self:?}"),
+        }
+    }
+
+    /// Picks the lobby to place a player into, according to the [`PlacementPolicy`].
+    /// The player is counted towards the lobby right away, so that a burst of players
+    /// is spread out before the lobby reports its new player count.
+    pub fn place(&mut self) -> Option<String> {
+        let index = self.pick()?;
+        let lobby = &mut self.lobbies[index];
+        lobby.players += 1;
+
+        trace!("lobby pool: placing player into {}", lobby.name);
+        Some(lobby.name.clone())
+    }
+
+    fn pick(&self) -> Option<usize> {
+        let lobbies = || self.lobbies.iter().enumerate().filter(|(_, l)| !l.draining);
+        let least_loaded = || lobbies().min_by_key(|(_, l)| l.players).map(|(i, _)| i);
+
+        match self.policy {
+            PlacementPolicy::LeastLoaded => least_loaded(),
+            PlacementPolicy::FillFirst => lobbies()
+                .find(|(_, l)| l.players < self.capacity)
+                .map(|(i, _)| i)
+                .or_else(least_loaded),
+        }
+    }
+
+    /// Figures out which lobby proxies should forward new players to. If it has
+    /// changed, returns the previously preferred lobby and the new one.
+    pub fn update_preferred(&mut self) -> Option<(Option<String>, String)> {
+        let preferred = self.lobbies[self.pick()?].name.clone();
+
+        if self.preferred.as_ref() == Some(&preferred) {
+            return None;
+        }
+
+        let previous = self.preferred.replace(preferred.clone());
+        Some((previous, preferred))
+    }
+
+    /// The lobby that proxies currently forward new players to, if any.
+    pub fn preferred(&self) -> Option<&str> {
+        self.preferred.as_deref()
+    }
+
+    pub fn queue(&mut self, player: Option<String>, reply: Option<RequestReply>) {
+        self.queue.push((player, reply));
+    }
+
+    pub fn drain_queue(&mut self) -> Vec<(Option<String>, Option<RequestReply>)> {
+        std::mem::take(&mut self.queue)
+    }
+
+    /// Whether any request is waiting on a lobby server to come online.
+    pub fn has_queue(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Decides whether lobby servers should be spawned or stopped, based on how
+    /// full the lobby servers are.
+    ///
+    /// A new lobby server is spawned once the average fill of every lobby crosses
+    /// the high-water mark. A lobby server that sits below the low-water mark for
+    /// long enough is drained: it won't get any new players, and it's stopped once
+    /// every player has left it.
+    pub fn autoscale(&mut self, now: Instant) -> Autoscale {
+        let mut autoscale = Autoscale::default();
+
+        let capacity = self.capacity;
+        let active = self.lobbies.iter().filter(|l| !l.draining);
+        let active_count = active.clone().count();
+
+        let players: u32 = active.map(|l| l.players).sum();
+        let average_fill = match active_count {
+            0 => 0.0,
+            n => players as f32 / (n as u32 * capacity.max(1)) as f32,
+        };
+
+        if average_fill >= self.scale_up_fill && !self.is_starting() {
+            // bring back a draining lobby before spawning a whole new one
+            if let Some(lobby) = self.lobbies.iter_mut().find(|l| l.draining) {
+                info!(
+                    "lobby pool: average fill {average_fill}, no longer draining {}",
+                    lobby.name
+                );
+                lobby.draining = false;
+                lobby.below_low_water_since = None;
+            } else if self.lobbies.len() + self.starting < self.max_lobbies {
+                info!("lobby pool: average fill {average_fill}, spawning another lobby");
+                self.reserve();
+                autoscale.spawn = true;
+            }
+        }
+
+        let mut can_drain = active_count.saturating_sub(self.min_lobbies);
+
+        for lobby in self.lobbies.iter_mut().filter(|l| !l.draining) {
+            if lobby.fill(capacity) >= self.scale_down_fill {
+                lobby.below_low_water_since = None;
+                continue;
+            }
+
+            let since = *lobby.below_low_water_since.get_or_insert(now);
+            if can_drain > 0 && now.duration_since(since) >= self.scale_down_after {
+                info!("lobby pool: draining lobby {}", lobby.name);
+                lobby.draining = true;
+                can_drain -= 1;
+            }
+        }
+
+        let (drained, lobbies): (Vec<_>, Vec<_>) = std::mem::take(&mut self.lobbies)
+            .into_iter()
+            .partition(|l| l.draining && l.players == 0);
+
+        self.lobbies = lobbies;
+
+        for lobby in drained {
+            info!("lobby pool: lobby {} has been drained", lobby.name);
+
+            if self.preferred.as_ref() == Some(&lobby.name) {
+                self.preferred = None;
+            }
+
+            autoscale.stop.push(lobby.name);
+        }
+
+        autoscale
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lobbies.is_empty()
+    }
+
+    pub fn is_starting(&self) -> bool {
+        self.starting > 0
+    }
+
+    /// Records that a lobby server is about to be spawned.
+    pub fn reserve(&mut self) {
+        self.starting += 1;
+    }
+
+    /// Records that a lobby server that was about to be spawned won't come online.
+    pub fn release(&mut self) {
+        self.starting = self.starting.saturating_sub(1);
+    }
+
+    /// Returns the amount of lobby servers that must be spawned to keep the
+    /// minimum amount of lobby servers online, and records them as spawning.
+    pub fn reserve_missing(&mut self) -> usize {
+        let online = self.lobbies.len() + self.starting;
+        let missing = self.min_lobbies.saturating_sub(online);
+
+        self.starting += missing;
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller_client::codec::MessagePack;
+    use std::sync::Arc;
+
+    fn pool(policy: PlacementPolicy) -> LobbyPool {
+        let config = Config {
+            lobby_placement: policy,
+            lobby_capacity: 2,
+            ..Config::default()
+        };
+
+        LobbyPool::new(&config)
+    }
+
+    fn autoscaling_pool(min_lobbies: usize, max_lobbies: usize) -> LobbyPool {
+        let config = Config {
+            lobby_capacity: 10,
+            min_lobbies,
+            max_lobbies,
+            lobby_scale_up_fill: 0.8,
+            lobby_scale_down_fill: 0.25,
+            lobby_scale_down_after: AFTER,
+            ..Config::default()
+        };
+
+        LobbyPool::new(&config)
+    }
+
+    const AFTER: Duration = Duration::from_secs(60);
+
+    /// Adds a lobby with `players` on it. Needs a runtime, as the writer of the
+    /// lobby runs in its own task.
+    fn push(pool: &mut LobbyPool, name: &str, players: u32) {
+        let addr = ([127, 0, 0, 1], 25565).into();
+        let writer = WriteChannel::new(tokio::io::sink(), addr, Arc::new(MessagePack), 8);
+
+        pool.push(name.to_owned(), writer);
+        pool.set_players(name, players);
+    }
+
+    #[tokio::test]
+    async fn no_lobby_to_place_into() {
+        let mut pool = pool(PlacementPolicy::LeastLoaded);
+        assert_eq!(pool.place(), None);
+    }
+
+    #[tokio::test]
+    async fn least_loaded_spreads_players() {
+        let mut pool = pool(PlacementPolicy::LeastLoaded);
+        push(&mut pool, "lobby-0", 1);
+        push(&mut pool, "lobby-1", 0);
+
+        assert_eq!(pool.place().as_deref(), Some("lobby-1"));
+        // both lobbies have one player now, so the first one wins the tie
+        assert_eq!(pool.place().as_deref(), Some("lobby-0"));
+        assert_eq!(pool.place().as_deref(), Some("lobby-1"));
+    }
+
+    #[tokio::test]
+    async fn fill_first_fills_the_oldest_lobby() {
+        let mut pool = pool(PlacementPolicy::FillFirst);
+        push(&mut pool, "lobby-0", 1);
+        push(&mut pool, "lobby-1", 0);
+
+        assert_eq!(pool.place().as_deref(), Some("lobby-0"));
+        assert_eq!(pool.place().as_deref(), Some("lobby-1"));
+        assert_eq!(pool.place().as_deref(), Some("lobby-1"));
+    }
+
+    #[tokio::test]
+    async fn fill_first_overflows_into_the_least_loaded_lobby() {
+        let mut pool = pool(PlacementPolicy::FillFirst);
+        push(&mut pool, "lobby-0", 3);
+        push(&mut pool, "lobby-1", 2);
+
+        assert_eq!(pool.place().as_deref(), Some("lobby-1"));
+    }
+
+    #[tokio::test]
+    async fn removed_lobbies_are_no_longer_preferred() {
+        let mut pool = pool(PlacementPolicy::LeastLoaded);
+        push(&mut pool, "lobby-0", 0);
+
+        let update = pool.update_preferred();
+        assert_eq!(update, Some((None, "lobby-0".to_owned())));
+        assert_eq!(pool.update_preferred(), None);
+
+        assert!(pool.remove("lobby-0"));
+        assert!(!pool.remove("lobby-0"));
+        assert_eq!(pool.preferred(), None);
+        assert_eq!(pool.place(), None);
+    }
+
+    #[tokio::test]
+    async fn scales_up_once_above_the_high_water_mark() {
+        let mut pool = autoscaling_pool(1, 8);
+        let now = Instant::now();
+        push(&mut pool, "lobby-0", 8);
+        push(&mut pool, "lobby-1", 8);
+
+        let autoscale = pool.autoscale(now);
+        assert!(autoscale.spawn);
+        assert!(pool.is_starting());
+
+        // the lobby that is starting will take the load, so don't spawn another
+        assert!(!pool.autoscale(now).spawn);
+    }
+
+    #[tokio::test]
+    async fn never_scales_up_beyond_the_maximum() {
+        let mut pool = autoscaling_pool(1, 2);
+        push(&mut pool, "lobby-0", 10);
+        push(&mut pool, "lobby-1", 10);
+
+        assert!(!pool.autoscale(Instant::now()).spawn);
+    }
+
+    #[tokio::test]
+    async fn drains_lobbies_below_the_low_water_mark_for_long_enough() {
+        let mut pool = autoscaling_pool(1, 8);
+        let now = Instant::now();
+        push(&mut pool, "lobby-0", 5);
+        push(&mut pool, "lobby-1", 1);
+
+        let autoscale = pool.autoscale(now);
+        assert!(autoscale.stop.is_empty());
+
+        // not below the low-water mark for long enough yet
+        let autoscale = pool.autoscale(now + AFTER / 2);
+        assert!(autoscale.stop.is_empty());
+
+        // draining, but a player is still on it
+        let autoscale = pool.autoscale(now + AFTER);
+        assert!(autoscale.stop.is_empty());
+        assert_eq!(pool.place().as_deref(), Some("lobby-0"));
+
+        pool.set_players("lobby-1", 0);
+        let autoscale = pool.autoscale(now + AFTER);
+        assert_eq!(autoscale.stop, ["lobby-1"]);
+        assert!(!pool.remove("lobby-1"));
+    }
+
+    #[tokio::test]
+    async fn going_above_the_low_water_mark_resets_the_timer() {
+        let mut pool = autoscaling_pool(0, 8);
+        let now = Instant::now();
+        push(&mut pool, "lobby-0", 0);
+
+        pool.autoscale(now);
+        pool.set_players("lobby-0", 5);
+        pool.autoscale(now + AFTER / 2);
+        pool.set_players("lobby-0", 0);
+
+        let autoscale = pool.autoscale(now + AFTER);
+        assert!(autoscale.stop.is_empty());
+
+        let autoscale = pool.autoscale(now + AFTER * 2);
+        assert_eq!(autoscale.stop, ["lobby-0"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_minimum_amount_of_lobbies() {
+        let mut pool = autoscaling_pool(1, 8);
+        let now = Instant::now();
+        push(&mut pool, "lobby-0", 0);
+        push(&mut pool, "lobby-1", 0);
+
+        pool.autoscale(now);
+        let autoscale = pool.autoscale(now + AFTER);
+        assert_eq!(autoscale.stop.len(), 1);
+
+        let autoscale = pool.autoscale(now + AFTER * 2);
+        assert!(autoscale.stop.is_empty());
+        assert!(pool.place().is_some());
+    }
+
+    #[tokio::test]
+    async fn brings_back_a_draining_lobby_before_spawning() {
+        let mut pool = autoscaling_pool(1, 8);
+        let now = Instant::now();
+        push(&mut pool, "lobby-0", 5);
+        push(&mut pool, "lobby-1", 1);
+
+        pool.autoscale(now);
+        pool.autoscale(now + AFTER);
+
+        // lobby-1 is draining, so lobby-0 is the only active lobby
+        pool.set_players("lobby-0", 9);
+        let autoscale = pool.autoscale(now + AFTER);
+        assert!(!autoscale.spawn);
+        assert!(!pool.is_starting());
+
+        pool.set_players("lobby-0", 0);
+        assert_eq!(pool.place().as_deref(), Some("lobby-0"));
+        assert_eq!(pool.place().as_deref(), Some("lobby-0"));
+        pool.set_players("lobby-0", 5);
+        assert_eq!(pool.place().as_deref(), Some("lobby-1"));
+    }
+
+    #[tokio::test]
+    async fn reserves_the_missing_lobbies() {
+        let mut pool = autoscaling_pool(2, 8);
+        push(&mut pool, "lobby-0", 0);
+
+        assert_eq!(pool.reserve_missing(), 1);
+        assert_eq!(pool.reserve_missing(), 0);
+
+        pool.release();
+        assert_eq!(pool.reserve_missing(), 1);
+    }
+
+    #[tokio::test]
+    async fn queues_players_until_they_are_drained() {
+        let mut pool = autoscaling_pool(0, 8);
+        assert!(!pool.has_queue());
+
+        pool.queue(Some("player".to_owned()), None);
+        assert!(pool.has_queue());
+
+        assert_eq!(pool.drain_queue().len(), 1);
+        assert!(!pool.has_queue());
+    }
+}
@@ -1,14 +1,22 @@
 use crate::brain::{BrainMsg, ConnectionInfo};
+use crate::http::QueueMetrics;
 use crate::transport::{Kind, Packet, WriteChannel, WriteChannelError};
 use derive_more::Display;
-use log::{info, trace, warn};
+use log::{error, info, trace, warn};
 use std::collections::VecDeque;
 use std::future::Future;
-use std::time::Duration;
-use tokio::sync::mpsc::error::SendError;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::oneshot;
 
+/// How often a cluster checks for servers that have been idle for too long.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a cluster at its maximum amount of servers waits before trying to
+/// find a server for a queue request again.
+const CAPACITY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
 #[derive(Display)]
 enum ClusterQueueState {
     /// The cluster is currently not attempting to queue players into a minigame server.
@@ -28,31 +36,72 @@ enum ClusterQueueState {
     /// [`Idle`]: ClusterQueueState::Idle
     #[display(fmt = "Starting")]
     Starting(oneshot::Sender<ServerName>),
+    /// No server wanted to accept players, but the cluster already has its maximum
+    /// amount of servers, so it can't instantiate a new one. After a short delay,
+    /// it will transition back into the [`RecvPong`] state to try again.
+    ///
+    /// [`RecvPong`]: ClusterQueueState::RecvPong
+    #[display(fmt = "AtCapacity")]
+    AtCapacity(oneshot::Sender<ServerName>),
+}
+
+/// Settings for a single minigame cluster.
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// The amount of idle minigame servers to keep booted. An idle server is
+    /// active, but hasn't had any players sent to it yet.
+    pub warm_pool: usize,
+    /// How long a server may be empty before it is stopped.
+    pub idle_timeout: Duration,
+    /// The least amount of servers to keep online, even if they're idle.
+    pub min_servers: usize,
+    /// The most servers that may be online (or starting) at once.
+    pub max_servers: usize,
+    /// How many messages may wait to be handled by the cluster.
+    pub queue_size: usize,
 }
 
 pub struct MinigameClusterHandle {
-    pub write: UnboundedSender<ClusterMsg>,
+    pub write: Sender<ClusterMsg>,
 }
 
 impl MinigameClusterHandle {
-    pub fn start(kind: String, to_brain: UnboundedSender<BrainMsg>) -> Self {
-        let (write, read) = unbounded_channel();
-        tokio::task::spawn(run_minigame_cluster(kind, to_brain, write.clone(), read));
+    pub fn start(
+        kind: String,
+        config: ClusterConfig,
+        to_brain: Sender<BrainMsg>,
+        queues: &QueueMetrics,
+    ) -> Self {
+        let (write, read) = channel(config.queue_size);
+        queues.register(format!("cluster/{kind}"), &write);
+
+        let to_brain = forward_to_brain(to_brain, config.queue_size);
+        queues.register(format!("cluster/{kind}/brain"), &to_brain);
+
+        tokio::task::spawn(run_minigame_cluster(
+            kind,
+            config,
+            to_brain,
+            write.clone(),
+            read,
+        ));
 
         MinigameClusterHandle { write }
     }
 
-    pub fn push_server(&self, server: MinigameServer) -> Result<(), SendError<ClusterMsg>> {
-        self.write.send(ClusterMsg::PushServer(server))
+    pub async fn push_server(&self, server: MinigameServer) -> Result<(), SendError<ClusterMsg>> {
+        self.write.send(ClusterMsg::PushServer(server)).await
     }
 
-    pub fn pop_server(&self, conn: ConnectionInfo) -> Result<(), SendError<ClusterMsg>> {
-        self.write.send(ClusterMsg::PopServer(conn))
+    pub async fn pop_server(&self, conn: ConnectionInfo) -> Result<(), SendError<ClusterMsg>> {
+        self.write.send(ClusterMsg::PopServer(conn)).await
     }
 
-    pub fn queue_server(&self) -> Result<impl Future<Output = ServerName>, SendError<ClusterMsg>> {
+    pub async fn queue_server(
+        &self,
+    ) -> Result<impl Future<Output = ServerName>, SendError<ClusterMsg>> {
         let (sender, receiver) = oneshot::channel();
-        self.write.send(ClusterMsg::QueueServer(sender))?;
+        self.write.send(ClusterMsg::QueueServer(sender)).await?;
         Ok(async move { receiver.await.expect("cannot fail") })
     }
 }
@@ -63,20 +112,52 @@ pub enum ClusterMsg {
     PopServer(ConnectionInfo),
     QueueServer(oneshot::Sender<ServerName>),
     TimerCompleted(i32),
-    UpdateActive { name: ServerName, active: bool },
+    CapacityRetry,
+    UpdateActive {
+        name: ServerName,
+        active: bool,
+    },
     ServerPong(i32, ServerName),
+    PlayerCount {
+        name: ServerName,
+        players: u32,
+    },
+    IdleCheck,
+    /// A server that was spawned for the cluster didn't connect in time.
+    SpawnExpired,
 }
 
 #[derive(Debug)]
 pub struct MinigameServer {
     pub name: String,
     pub active: bool,
+    /// Whether any player has been sent to this server yet.
+    pub assigned: bool,
+    pub players: u32,
+    /// Since when the server has been empty, if it is.
+    pub idle_since: Option<Instant>,
     pub writer: WriteChannel,
 }
 
 impl MinigameServer {
-    pub async fn ping(&mut self, timer: i32) -> Result<(), WriteChannelError> {
-        self.writer.write_next(&Packet::Ping { timer }).await
+    pub fn new(name: String, writer: WriteChannel) -> Self {
+        Self {
+            name,
+            active: true,
+            assigned: false,
+            players: 0,
+            idle_since: None,
+            writer,
+        }
+    }
+
+    /// A warm server can take players right away, and is part of the warm pool.
+    pub fn is_warm(&self) -> bool {
+        self.active && !self.assigned
+    }
+
+    pub fn ping(&self, timer: i32) -> Result<(), WriteChannelError> {
+        self.writer.send(&Packet::Ping { timer })
     }
 }
 
@@ -85,9 +166,10 @@ pub struct ServerName(#[display(fmt = "{}")] pub String);
 
 async fn run_minigame_cluster(
     kind: String,
-    to_brain: UnboundedSender<BrainMsg>,
-    writer: UnboundedSender<ClusterMsg>,
-    mut reader: UnboundedReceiver<ClusterMsg>,
+    config: ClusterConfig,
+    to_brain: Sender<BrainMsg>,
+    writer: Sender<ClusterMsg>,
+    mut reader: Receiver<ClusterMsg>,
 ) -> Result<(), ()> {
     info!("cluster {kind}: started");
 
@@ -107,29 +189,74 @@ async fn run_minigame_cluster(
     // queue requests we can't handle
     let mut queue_reqs = VecDeque::new();
 
+    // messages the cluster sends to itself. these are handled before anything
+    // else in the queue, as the cluster can't wait for room in its own queue.
+    let mut followups = VecDeque::new();
+
     // if no server sends a `Pong` reply, we receive a `TimerCompleted` message,
     // but we need some way to ensure we can ignore TimerCompleted messages if we
     // do receive a Pong reply and the timer thread doesn't know about it.
     let mut timer_now: i32 = 0;
 
-    while let Some(msg) = reader.recv().await {
+    // the amount of servers we've asked the brain to spawn, which haven't connected yet
+    let mut starting: usize = 0;
+
+    // keeping idle servers booted means players don't have to wait for a server to
+    // start, so make sure there are enough of them (and the minimum amount of
+    // servers) from the get go
+    spawn_missing_servers(&kind, &config, &servers, &mut starting, &to_brain);
+
+    // periodically look for servers that have been idle for too long
+    let idle_check_writer = writer.clone();
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if idle_check_writer.send(ClusterMsg::IdleCheck).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    loop {
+        let msg = match followups.pop_front() {
+            Some(msg) => msg,
+            None => match reader.recv().await {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+
         trace!("cluster {kind}: received message {msg:?}");
 
         match msg {
-            ClusterMsg::PushServer(server) => {
+            ClusterMsg::PushServer(mut server) => {
                 let name = server.name.clone();
 
+                // the server is taken by the player that has been waiting for it,
+                // just like a server that answered a ping
+                if matches!(state, ClusterQueueState::Starting(_)) {
+                    server.assigned = true;
+                    server.players += 1;
+                }
+
                 info!("cluster {kind}: adding server {server:?}. current servers: {servers:?}");
                 servers.push(server);
+                starting = starting.saturating_sub(1);
 
                 if let ClusterQueueState::Starting(server) = state {
+                    // the server is no longer warm, so replace it in the warm pool
+                    spawn_missing_servers(&kind, &config, &servers, &mut starting, &to_brain);
+
                     state = ClusterQueueState::Idle;
                     server.send(ServerName(name)).expect("expect to respond");
 
                     // we are now idle: re-queue work if necessary
                     if let Some(respond) = queue_reqs.pop_front() {
                         trace!("cluster {kind}: detected idle state with server queue requests, priming msg loop");
-                        writer.send(ClusterMsg::QueueServer(respond)).unwrap();
+                        followups.push_back(ClusterMsg::QueueServer(respond));
                     }
                 }
             }
@@ -143,19 +270,106 @@ async fn run_minigame_cluster(
                         warn!("cluster {kind}: unable to find a minigame server for server connection {server:?}. current servers: {servers:?}");
                     }
                 }
+
+                spawn_missing_servers(&kind, &config, &servers, &mut starting, &to_brain);
             }
             ClusterMsg::UpdateActive {
                 name: ServerName(name),
                 active,
-            } => match servers.iter_mut().find(|s| s.name == name) {
-                Some(s) => {
-                    s.active = active;
-                    info!("cluster {kind}: update server {name}'s active state to: {active}")
+            } => {
+                match servers.iter_mut().find(|s| s.name == name) {
+                    Some(s) => {
+                        s.active = active;
+                        info!("cluster {kind}: update server {name}'s active state to: {active}")
+                    }
+                    None => {
+                        warn!("cluster {kind}: unable to find server {name}. current servers: {servers:?}");
+                    }
                 }
+
+                spawn_missing_servers(&kind, &config, &servers, &mut starting, &to_brain);
+            }
+            ClusterMsg::PlayerCount {
+                name: ServerName(name),
+                players,
+            } => match servers.iter_mut().find(|s| s.name == name) {
+                Some(s) => s.players = players,
                 None => {
                     warn!("cluster {kind}: unable to find server {name}. current servers: {servers:?}");
                 }
             },
+            ClusterMsg::IdleCheck => {
+                let now = Instant::now();
+
+                // servers stop accepting players while a game is running, so only
+                // the players decide whether a server is idle
+                for server in servers.iter_mut() {
+                    if server.players > 0 {
+                        server.idle_since = None;
+                    } else {
+                        server.idle_since.get_or_insert(now);
+                    }
+                }
+
+                // warm servers are idle by design, so keep enough of them around,
+                // and never go below the minimum amount of servers
+                let mut keep_warm = config.warm_pool;
+                let mut can_stop = servers.len().saturating_sub(config.min_servers);
+                let mut stopping = Vec::new();
+
+                // every active server was pinged, and may be about to take a player
+                let pinged = matches!(state, ClusterQueueState::RecvPong(_));
+
+                servers.retain(|server| {
+                    if server.is_warm() && keep_warm > 0 {
+                        keep_warm -= 1;
+                        return true;
+                    }
+
+                    if pinged && server.active {
+                        return true;
+                    }
+
+                    let timed_out = matches!(server.idle_since, Some(since) if now.duration_since(since) >= config.idle_timeout);
+                    if !timed_out || can_stop == 0 {
+                        return true;
+                    }
+
+                    can_stop -= 1;
+                    stopping.push(server.name.clone());
+                    false
+                });
+
+                for name in stopping {
+                    info!("cluster {kind}: server {name} has been idle for too long, stopping it");
+                    notify_brain(&to_brain, BrainMsg::StopServer { name });
+                }
+            }
+            ClusterMsg::SpawnExpired => {
+                starting = starting.saturating_sub(1);
+
+                // a player may be waiting for the server that never came
+                state = match state {
+                    ClusterQueueState::Starting(server) if starting == 0 => {
+                        if servers.len() < config.max_servers {
+                            starting += 1;
+
+                            let spawn = BrainMsg::Spawn {
+                                kind: Kind::Minigame { kind: kind.clone() },
+                            };
+
+                            notify_brain(&to_brain, spawn);
+                            ClusterQueueState::Starting(server)
+                        } else {
+                            retry_at_capacity(&kind, &config, &writer);
+                            ClusterQueueState::AtCapacity(server)
+                        }
+                    }
+                    state => state,
+                };
+
+                spawn_missing_servers(&kind, &config, &servers, &mut starting, &to_brain);
+            }
             //
             // from here on, these are messages relating to queueing players
             // into a minigame server.
@@ -173,32 +387,18 @@ async fn run_minigame_cluster(
                 // soon as possible.
                 state = ClusterQueueState::RecvPong(server);
 
-                // ping all active servers
-                let active_servers = servers.iter_mut().filter(|s| s.active);
-                for server in active_servers {
-                    let ping = server.ping(timer_now).await;
+                start_ping_round(&kind, &servers, timer_now, &writer);
+            }
+            ClusterMsg::CapacityRetry => {
+                let ClusterQueueState::AtCapacity(server) = state else {
+                    trace!("cluster {kind}: late CapacityRetry detected");
+                    continue;
+                };
 
-                    if let Err(err) = ping {
-                        warn!("cluster {kind}: couldn't send ping to {server:?}: {err}");
-                    }
-                }
+                // a server may be willing to accept players by now
+                state = ClusterQueueState::RecvPong(server);
 
-                // now, we are waiting to receive pings.
-                // if we don't receive any pings, we will be stuck here forever.
-
-                // start a timer if no servers respond
-                let writer = writer.clone();
-                let kind = kind.clone();
-                let timer = timer_now;
-                tokio::task::spawn(async move {
-                    trace!("cluster {kind} timer {timer}: starting now");
-                    tokio::time::sleep(Duration::from_secs(1)).await;
-
-                    trace!("cluster {kind} timer {timer}: done");
-                    writer
-                        .send(ClusterMsg::TimerCompleted(timer))
-                        .expect("expected to send cluster msg");
-                });
+                start_ping_round(&kind, &servers, timer_now, &writer);
             }
             ClusterMsg::ServerPong(timer, name) => {
                 if timer != timer_now {
@@ -206,12 +406,25 @@ async fn run_minigame_cluster(
                     continue;
                 }
 
+                // the server may have been stopped since it was pinged
+                let Some(pong_server) = servers.iter_mut().find(|s| s.name == name.0) else {
+                    trace!("cluster {kind}: ServerPong from unknown server {name}");
+                    continue;
+                };
+
                 // we only want to handle server pongs when we are receiving pongs
                 let ClusterQueueState::RecvPong(server) = state else {
                     trace!("cluster {kind}: late ServerPong detected");
                     continue;
                 };
 
+                // the server is no longer warm, so replace it in the warm pool. we
+                // count the player right away so the server isn't considered empty
+                pong_server.assigned = true;
+                pong_server.players += 1;
+
+                spawn_missing_servers(&kind, &config, &servers, &mut starting, &to_brain);
+
                 // send the player to the server
                 server.send(name).expect("expected to respond to query");
 
@@ -223,7 +436,7 @@ async fn run_minigame_cluster(
                 // we are now idle: re-queue work if necessary
                 if let Some(respond) = queue_reqs.pop_front() {
                     trace!("cluster {kind}: detected idle state with server queue requests, priming msg loop");
-                    writer.send(ClusterMsg::QueueServer(respond)).unwrap();
+                    followups.push_back(ClusterMsg::QueueServer(respond));
                 }
             }
             ClusterMsg::TimerCompleted(timer) => {
@@ -244,15 +457,25 @@ async fn run_minigame_cluster(
 
                 // if we get a `ServerPong` after this TimerCompleted, we want to
                 // ignore the pong.
-                state = ClusterQueueState::Starting(server);
                 timer_now = timer_now.wrapping_add(1); // ignore older events
 
-                // tell the brain to start a new server
-                let spawn = BrainMsg::Spawn {
-                    kind: Kind::Minigame { kind: kind.clone() },
-                };
-
-                to_brain.send(spawn).expect("expected to send brain msg");
+                if starting > 0 {
+                    // a server is already on its way (e.g. for the warm pool)
+                    state = ClusterQueueState::Starting(server);
+                } else if servers.len() < config.max_servers {
+                    // tell the brain to start a new server
+                    state = ClusterQueueState::Starting(server);
+                    starting += 1;
+
+                    let spawn = BrainMsg::Spawn {
+                        kind: Kind::Minigame { kind: kind.clone() },
+                    };
+
+                    notify_brain(&to_brain, spawn);
+                } else {
+                    state = ClusterQueueState::AtCapacity(server);
+                    retry_at_capacity(&kind, &config, &writer);
+                }
             }
         }
     }
@@ -260,3 +483,120 @@ async fn run_minigame_cluster(
     info!("minigame cluster {kind} ending");
     Ok(())
 }
+
+/// Waits a bit before looking for a server again, as the cluster can't start any
+/// more servers. Until then, a server may become willing to accept players.
+fn retry_at_capacity(kind: &str, config: &ClusterConfig, writer: &Sender<ClusterMsg>) {
+    trace!(
+        "cluster {kind}: at the maximum of {} servers, waiting for capacity",
+        config.max_servers
+    );
+
+    let writer = writer.clone();
+    tokio::task::spawn(async move {
+        tokio::time::sleep(CAPACITY_RETRY_DELAY).await;
+        writer
+            .send(ClusterMsg::CapacityRetry)
+            .await
+            .expect("expected to send cluster msg");
+    });
+}
+
+/// Pings every active server to ask if any of them is willing to accept players,
+/// and starts a timer for when no server responds.
+fn start_ping_round(
+    kind: &str,
+    servers: &[MinigameServer],
+    timer_now: i32,
+    writer: &Sender<ClusterMsg>,
+) {
+    // ping all active servers
+    let active_servers = servers.iter().filter(|s| s.active);
+    for server in active_servers {
+        let ping = server.ping(timer_now);
+
+        if let Err(err) = ping {
+            warn!("cluster {kind}: couldn't send ping to {server:?}: {err}");
+        }
+    }
+
+    // now, we are waiting to receive pings.
+    // if we don't receive any pings, we will be stuck here forever.
+
+    // start a timer if no servers respond
+    let writer = writer.clone();
+    let kind = kind.to_owned();
+    let timer = timer_now;
+    tokio::task::spawn(async move {
+        trace!("cluster {kind} timer {timer}: starting now");
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        trace!("cluster {kind} timer {timer}: done");
+        writer
+            .send(ClusterMsg::TimerCompleted(timer))
+            .await
+            .expect("expected to send cluster msg");
+    });
+}
+
+/// Asks the brain to spawn servers until there are enough idle servers (or servers
+/// on their way) to fill up the warm pool, and at least the minimum amount of
+/// servers. Never spawns more than the maximum amount of servers.
+fn spawn_missing_servers(
+    kind: &str,
+    config: &ClusterConfig,
+    servers: &[MinigameServer],
+    starting: &mut usize,
+    to_brain: &Sender<BrainMsg>,
+) {
+    let total = servers.len() + *starting;
+    let warm = servers.iter().filter(|s| s.is_warm()).count();
+
+    let missing_warm = config.warm_pool.saturating_sub(warm + *starting);
+    let missing_min = config.min_servers.saturating_sub(total);
+    let missing = missing_warm
+        .max(missing_min)
+        .min(config.max_servers.saturating_sub(total));
+
+    for _ in 0..missing {
+        trace!("cluster {kind}: spawning a server to keep enough servers around");
+        *starting += 1;
+
+        let spawn = BrainMsg::Spawn {
+            kind: Kind::Minigame {
+                kind: kind.to_owned(),
+            },
+        };
+
+        notify_brain(to_brain, spawn);
+    }
+}
+
+/// Starts a task that passes messages on to the brain, and returns the queue of
+/// that task. The brain may be waiting for room in the queue of a cluster, so a
+/// cluster can't wait for room in the queue of the brain itself.
+fn forward_to_brain(to_brain: Sender<BrainMsg>, capacity: usize) -> Sender<BrainMsg> {
+    let (sender, mut receiver) = channel(capacity);
+
+    tokio::task::spawn(async move {
+        while let Some(msg) = receiver.recv().await {
+            if let Err(SendError(msg)) = to_brain.send(msg).await {
+                error!("couldn't send {msg:?} to the brain");
+                break;
+            }
+        }
+    });
+
+    sender
+}
+
+/// Sends a message to the brain without waiting, through the task started by
+/// [`forward_to_brain`]. Once the brain is so far behind that the task can't keep
+/// up either, the message is dropped.
+fn notify_brain(to_brain: &Sender<BrainMsg>, msg: BrainMsg) {
+    match to_brain.try_send(msg) {
+        Ok(()) => {}
+        Err(TrySendError::Full(msg)) => error!("the brain is overloaded, dropping {msg:?}"),
+        Err(TrySendError::Closed(msg)) => error!("couldn't send {msg:?} to the brain"),
+    }
+}
@@ -0,0 +1,206 @@
+use crate::transport::{Packet, COMPRESSED_FLAG, FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+use serde_json::json;
+
+/// Builds a JSON Schema document describing every [`Packet`], along with the
+/// types used in them.
+///
+/// JSON Schema can't describe how packets are put on the wire, so that is described
+/// by the `x-framing` extension, and the protocol version and features by the
+/// `x-protocol` extension.
+pub fn export() -> RootSchema {
+    let mut schema = schema_for!(Packet);
+    let extensions = &mut schema.schema.extensions;
+
+    extensions.insert(
+        "x-framing".to_owned(),
+        json!({
+            "header": "u32, big endian",
+            "length_mask": !COMPRESSED_FLAG,
+            "compressed_flag": COMPRESSED_FLAG,
+            "compression": "zstd, only once both sides agreed on the `compression` feature",
+            "codecs": {
+                "messagepack": "the default, with structs encoded as maps",
+                "json": "used if the first frame of the connection starts with `{`",
+            },
+        }),
+    );
+
+    extensions.insert(
+        "x-protocol".to_owned(),
+        json!({
+            "version": PROTOCOL_VERSION,
+            "min_version": MIN_PROTOCOL_VERSION,
+            "features": FEATURES,
+        }),
+    );
+
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{Kind, Redacted, ServerLink};
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+
+    /// One of every packet. The match makes sure that a packet can't be added
+    /// without being added here too.
+    fn every_packet() -> Vec<Packet> {
+        let link = ServerLink {
+            name: "lobby-0".to_owned(),
+            address: "127.0.0.1".to_owned(),
+            port: 25565,
+            priority: 1,
+        };
+
+        let packets = vec![
+            Packet::Authentication {
+                name: "lobby-0".to_owned(),
+                kind: Kind::Lobby,
+                ip: "/127.0.0.1:25565".to_owned(),
+                version: PROTOCOL_VERSION,
+                features: Vec::new(),
+                token: Some(Redacted("token".to_owned())),
+            },
+            Packet::Challenge {
+                nonce: "nonce".to_owned(),
+            },
+            Packet::ChallengeResponse {
+                signature: Redacted("signature".to_owned()),
+            },
+            Packet::AuthenticationAccepted {
+                version: PROTOCOL_VERSION,
+                features: Vec::new(),
+            },
+            Packet::AuthenticationRejected {
+                version: PROTOCOL_VERSION,
+                reason: "reason".to_owned(),
+            },
+            Packet::Request {
+                kind: Kind::Minigame {
+                    kind: "bedwars".to_owned(),
+                },
+                player: Some("player".to_owned()),
+                id: Some(0),
+            },
+            Packet::RequestQueued { id: 0 },
+            Packet::RequestFulfilled {
+                id: 0,
+                server: "lobby-0".to_owned(),
+            },
+            Packet::RequestFailed {
+                id: 0,
+                reason: "reason".to_owned(),
+            },
+            Packet::LinkServer {
+                name: link.name.clone(),
+                address: link.address.clone(),
+                port: link.port,
+                priority: link.priority,
+            },
+            Packet::UnlinkServer {
+                name: "lobby-0".to_owned(),
+            },
+            Packet::SyncServers {
+                servers: vec![link],
+            },
+            Packet::TransportPlayer {
+                player: "player".to_owned(),
+                to: "lobby-0".to_owned(),
+            },
+            Packet::Ping { timer: 0 },
+            Packet::Pong { timer: 0 },
+            Packet::UpdateActive { active: true },
+            Packet::PlayerJoined {
+                player: "player".to_owned(),
+            },
+            Packet::PlayerLeft {
+                player: "player".to_owned(),
+            },
+            Packet::Heartbeat { sent_at: 0 },
+            Packet::HeartbeatAck { sent_at: 0 },
+            Packet::PlayerCount { players: 0 },
+        ];
+
+        for packet in &packets {
+            match packet {
+                Packet::Authentication { .. }
+                | Packet::Challenge { .. }
+                | Packet::ChallengeResponse { .. }
+                | Packet::AuthenticationAccepted { .. }
+                | Packet::AuthenticationRejected { .. }
+                | Packet::Request { .. }
+                | Packet::RequestQueued { .. }
+                | Packet::RequestFulfilled { .. }
+                | Packet::RequestFailed { .. }
+                | Packet::LinkServer { .. }
+                | Packet::UnlinkServer { .. }
+                | Packet::SyncServers { .. }
+                | Packet::TransportPlayer { .. }
+                | Packet::Ping { .. }
+                | Packet::Pong { .. }
+                | Packet::UpdateActive { .. }
+                | Packet::PlayerJoined { .. }
+                | Packet::PlayerLeft { .. }
+                | Packet::Heartbeat { .. }
+                | Packet::HeartbeatAck { .. }
+                | Packet::PlayerCount { .. } => {}
+            }
+        }
+
+        packets
+    }
+
+    /// The name of every packet in the schema, along with the fields it has.
+    fn exported_packets() -> Vec<(String, BTreeSet<String>)> {
+        let schema = serde_json::to_value(export()).unwrap();
+
+        let variants = schema["oneOf"].as_array().expect("packets are a oneOf");
+        variants
+            .iter()
+            .map(|variant| {
+                let name = variant["required"][0].as_str().unwrap().to_owned();
+                let fields = variant["properties"][&name]["properties"]
+                    .as_object()
+                    .map(|fields| fields.keys().cloned().collect())
+                    .unwrap_or_default();
+
+                (name, fields)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exports_every_packet() {
+        let exported = exported_packets();
+        let packets = every_packet();
+        assert_eq!(exported.len(), packets.len());
+
+        for packet in packets {
+            let json = serde_json::to_value(&packet).unwrap();
+            let (name, fields) = json.as_object().unwrap().iter().next().unwrap();
+
+            let Some((_, exported_fields)) = exported.iter().find(|(n, _)| n == name) else {
+                panic!("{name} is missing from the schema");
+            };
+
+            let fields: BTreeSet<String> = match fields {
+                Value::Object(fields) => fields.keys().cloned().collect(),
+                _ => BTreeSet::new(),
+            };
+
+            assert_eq!(&fields, exported_fields, "fields of {name}");
+        }
+    }
+
+    #[test]
+    fn describes_the_protocol() {
+        let schema = serde_json::to_value(export()).unwrap();
+
+        assert_eq!(schema["x-protocol"]["version"], PROTOCOL_VERSION);
+        assert_eq!(schema["x-framing"]["compressed_flag"], COMPRESSED_FLAG);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::config::Config;
+use crate::transport::Kind;
+use log::{info, warn};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("couldn't read {0}: {1}")]
+    ReadFile(String, std::io::Error),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(String),
+    #[error("TLS_CERT and TLS_KEY must both be set to enable TLS")]
+    Incomplete,
+    #[error("client certificates require TLS to be enabled")]
+    ClientCaWithoutTls,
+    #[error("invalid client CA certificate: {0}")]
+    ClientCa(#[from] tokio_rustls::rustls::Error),
+    #[error("invalid client verifier: {0}")]
+    Verifier(#[from] tokio_rustls::rustls::server::VerifierBuilderError),
+}
+
+/// Builds the TLS acceptor that is used for every incoming connection, or `None`
+/// if TLS isn't configured.
+///
+/// If `TLS_CLIENT_CA` is set, every client must present a certificate signed by
+/// that CA, and the certificate decides which [`Kind`]s the client may
+/// authenticate as. See [`ClientIdentity`].
+pub fn acceptor(config: &Config) -> Result<Option<TlsAcceptor>, TlsError> {
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if config.tls_client_ca.is_some() => return Err(TlsError::ClientCaWithoutTls),
+        (None, None) => return Ok(None),
+        _ => return Err(TlsError::Incomplete),
+    };
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+
+    let builder = match &config.tls_client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(ca)? {
+                roots.add(cert)?;
+            }
+
+            info!("requiring client certificates signed by {}", ca.display());
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder.with_single_cert(read_certs(cert)?, read_key(key)?)?;
+
+    info!("TLS enabled with certificate {}", cert.display());
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+/// Who a client is according to its certificate. The common name of the certificate
+/// is the [`Kind`] that the client may authenticate as, such as `proxy`, `lobby`,
+/// `limbo` or `minigame-skywars`. A common name of `minigame` allows every kind of
+/// minigame server.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    pub common_name: String,
+}
+
+impl ClientIdentity {
+    /// Reads the identity from the certificate the client presented, if it did.
+    pub fn of(stream: &TlsStream<TcpStream>) -> Option<Self> {
+        let (_, connection) = stream.get_ref();
+        let cert = connection.peer_certificates()?.first()?;
+
+        let (_, cert) = match X509Certificate::from_der(cert) {
+            Ok(cert) => cert,
+            Err(err) => {
+                warn!("couldn't parse client certificate: {err}");
+                return None;
+            }
+        };
+
+        let common_name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+
+        Some(Self {
+            common_name: common_name.to_owned(),
+        })
+    }
+
+    pub fn allows(&self, kind: &Kind) -> bool {
+        match kind {
+            Kind::Minigame { .. } if self.common_name == "minigame" => true,
+            kind => self.common_name == kind.to_string(),
+        }
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let file = open(path)?;
+
+    rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<_, _>>()
+        .map_err(|err| TlsError::ReadFile(path.display().to_string(), err))
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let file = open(path)?;
+
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| TlsError::ReadFile(path.display().to_string(), err))?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.display().to_string()))
+}
+
+fn open(path: &Path) -> Result<File, TlsError> {
+    File::open(path).map_err(|err| TlsError::ReadFile(path.display().to_string(), err))
+}
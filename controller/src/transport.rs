@@ -1,229 +1,222 @@
-use derive_more::Display;
-use log::{error, trace};
-use rmp_serde::{Deserializer, Serializer};
-use serde::{Deserialize, Serialize};
+use controller_client::codec::{Codec, CodecError, MessagePack};
+use controller_client::frame::{read_frame, write_frame, ReadFrameError};
+use log::{error, trace, warn};
+use std::fmt;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use thiserror::Error;
-use tokio::io::{AsyncWriteExt, BufReader};
-use tokio::{
-    io::{AsyncReadExt, BufWriter},
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
-};
-
-/// A connection between a given server and the controller will **only** communicate
-/// in [`Packet`]s. Some packets are not expected to always be able to be sent in
-/// specific states, and would be unacceptable to do so.
-#[derive(Debug, Serialize, Deserialize)]
-pub enum Packet {
-    /// The [`Authentication`] packet is sent from the client to the controller to
-    /// establish the connection. It contains identifying information about the server,
-    /// so that the controller can appropriately handle the messages it may send.
-    ///
-    /// [`Authentication`]: Packet::Authentication
-    Authentication {
-        name: String,
-        kind: Kind,
-        ip: String,
-    },
-    /// The [`Request`] packet is sent from the client to the controller when the
-    /// client wants to make the controller aware of a request that a player wants
-    /// to join a specific kind of server. In the event that no player is specified,
-    /// this is a request to ensure a server of the specified kind is established.
-    ///
-    /// [`Request`]: Packet::Request
-    Request {
-        kind: Kind,
-        /// The UUID of the player that wants to connect to the desired server,
-        /// if applicable.
-        player: Option<String>,
-    },
-    /// The [`LinkServer`] packet is sent from the controller to the client that
-    /// is designated as the proxy server. This is sent to the proxy server upon
-    /// a connection so that the proxy server can dynamically add new servers for
-    /// players to connect to.
-    ///
-    /// [`LinkServer`]: Packet::LinkServer
-    LinkServer {
-        name: String,
-        address: String,
-        port: u16,
-        /// The priority is used to define the order in which linked server takes
-        /// precedence over others. The server with the highest priority has all
-        /// players forwarded to it on join.
-        ///
-        /// # Examples
-        ///
-        /// For every numeric list item, we will state the priority and server name,
-        /// and then state the server chosen that the proxy will forward players to.
-        ///
-        /// 1. `1 - "limbo"`, **Server:** `"limbo"`
-        ///
-        ///    The only server connected is `"limbo"` with a priority of 1, so all
-        ///    players will be forwarded to it.
-        ///
-        /// 2. `2 - "lobby"`, **Server:** `"lobby"`
-        ///
-        ///    Now there are two servers: `"limbo"` with priority 1 and `"lobby"`
-        ///    with priority 2. Because `"lobby"` has the higher priority, the proxy
-        ///    will connect players to it.
-        ///
-        /// 3. `0 - "minigame-0"`, **Server:** `"lobby"`
-        ///
-        ///    Because `"lobby"` still has the highest priority (2) than any other
-        ///    server (`"limbo"`: 1, `"minigame-0"`: 0), the proxy will still forward
-        ///    all players to that server.
-        priority: u16,
-    },
-    /// The [`UnlinkServer`] packet is sent from the controller to the client that
-    /// is designated as the proxy server when a connection to the controller is
-    /// terminated. If the controller is unable to reach a server, it is safe to
-    /// assume that the unreachable server is dead. Therefore, we don't want players
-    /// routed to that server in any circumstance, so we inform the proxy to unlink
-    /// the connection to that server.
-    ///
-    /// [`UnlinkServer`]: Packet::UnlinkServer
-    UnlinkServer { name: String },
-    /// The [`TransportPlayer`] packet is sent from the controller to the client that
-    /// is designated as the proxy server when a player is to be transported to
-    /// another server. There may be any number of reasons behind the transport, but
-    /// the most likely reason is that a [`Request`] packet was able to be fulfilled.
-    ///
-    /// [`TransportPlayer`]: Packet::TransportPlayer
-    /// [`Request`]: Packet::Request
-    TransportPlayer { player: String, to: String },
-    /// The [`Ping`] packet is sent from the controller (specifically, a minigame
-    /// cluster) to a minigame client to ask it if it is accepting players. The
-    /// first minigame server to respond with a [`Pong`] packet will have a player
-    /// transported to it to participate in the minigame. Servers that do not want
-    /// to accept players should not respond to the [`Ping`] packet.
-    ///
-    /// [`Ping`]: Packet::Ping
-    /// [`Pong`]: Packet::Pong
-    Ping { timer: i32 },
-    /// The [`Pong`] packet is sent from a minigame server to the controller (specifically,
-    /// a minigame cluster) only after a [`Ping`] packet has been sent. Sending a
-    /// [`Pong`] packet indicates that a server is willing to accept more players,
-    /// but servers that do not want players should simply not respond to the [`Ping`]
-    /// packet.
-    ///
-    /// [`Ping`]: Packet::Ping
-    /// [`Pong`]: Packet::Pong
-    Pong { timer: i32 },
-    /// The [`UpdateActive`] packet is sent from a minigame server to the controller
-    /// (specifically, a minigame cluster) when the minigame server wants to change
-    /// whether or not it's "active". An inactive minigame server will not receive
-    /// [`Ping`]s, whereas an active minigame server will receive [`Ping`]s.
-    ///
-    /// [`Ping`]: Packet::Ping
-    /// [`UpdateActive`]: Packet::UpdateActive
-    UpdateActive { active: bool },
-}
-
-impl Packet {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, rmp_serde::decode::Error> {
-        Packet::deserialize(&mut Deserializer::new(bytes))
-    }
-
-    pub fn to_bytes(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
-        let mut buffer = Vec::new();
-
-        self.serialize(&mut Serializer::new(&mut buffer).with_struct_map())
-            .map(|_| buffer)
-    }
-}
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::AbortHandle;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Display)]
-#[serde(tag = "tag", content = "payload")]
-pub enum Kind {
-    #[display(fmt = "limbo")]
-    Limbo,
-    #[display(fmt = "proxy")]
-    Proxy,
-    #[display(fmt = "lobby")]
-    Lobby,
-    #[display(fmt = "minigame-{kind}")]
-    Minigame { kind: String },
-}
+// the protocol itself is shared with clients, through the client SDK
+pub use controller_client::frame::{FrameError, COMPRESSED_FLAG};
+pub use controller_client::protocol::{
+    Kind, Packet, Redacted, ServerLink, FEATURES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 
-impl Kind {
-    pub fn priority(&self) -> u16 {
-        match self {
-            Kind::Lobby => 2,
-            Kind::Limbo => 1,
-            Kind::Minigame { .. } => 0,
-            Kind::Proxy => unreachable!(),
-        }
+/// The priority that servers of `kind` are linked with on proxies. Proxies can't be
+/// linked.
+pub fn link_priority(kind: &Kind) -> u16 {
+    match kind {
+        Kind::Lobby => 2,
+        Kind::Limbo => 1,
+        Kind::Minigame { .. } => 0,
+        Kind::Proxy => unreachable!(),
     }
 }
 
-pub struct ReadChannel {
-    reader: BufReader<OwnedReadHalf>,
+pub struct ReadChannel<R> {
+    reader: BufReader<R>,
+    max_frame_size: u32,
+    codec: Arc<dyn Codec>,
 }
 
 #[derive(Error, Debug)]
 pub enum ReadChannelError {
     #[error("IO error: {_0}")]
     IoError(#[from] std::io::Error),
-    #[error("Serde error: {_0}")]
-    SerdeError(#[from] rmp_serde::decode::Error),
+    #[error("Codec error: {_0}")]
+    CodecError(#[from] CodecError),
+    #[error("Malformed frame: {_0}")]
+    MalformedFrame(#[from] FrameError),
+}
+
+impl From<ReadFrameError> for ReadChannelError {
+    fn from(err: ReadFrameError) -> Self {
+        match err {
+            ReadFrameError::IoError(err) => ReadChannelError::IoError(err),
+            // peers that send these are most likely not servers at all
+            ReadFrameError::MalformedFrame(err) => ReadChannelError::MalformedFrame(err),
+        }
+    }
 }
 
-impl ReadChannel {
-    pub fn new(read_half: OwnedReadHalf) -> Self {
+impl<R: AsyncRead + Unpin> ReadChannel<R> {
+    /// Creates a channel that reads from `read_half`, decoding packets as MessagePack
+    /// until another codec is set.
+    pub fn new(read_half: R, max_frame_size: u32) -> Self {
         Self {
             reader: BufReader::new(read_half),
+            max_frame_size,
+            codec: Arc::new(MessagePack),
         }
     }
 
-    pub async fn read_next(&mut self) -> Result<Packet, ReadChannelError> {
-        let length = self.reader.read_u32().await?;
+    pub fn set_codec(&mut self, codec: Arc<dyn Codec>) {
+        self.codec = codec;
+    }
 
-        let mut buffer = vec![0; length as usize];
-        self.reader.read_exact(&mut buffer).await?;
+    pub async fn read_next(&mut self) -> Result<Packet, ReadChannelError> {
+        let frame = self.read_frame().await?;
+        Ok(self.codec.decode(&frame)?)
+    }
 
-        Ok(Packet::from_bytes(&buffer)?)
+    /// Reads the contents of the next frame, decompressed but not decoded yet.
+    pub async fn read_frame(&mut self) -> Result<Vec<u8>, ReadChannelError> {
+        Ok(read_frame(&mut self.reader, self.max_frame_size).await?)
     }
 }
 
-#[derive(Debug)]
+/// Writes [`Packet`]s to a peer. The actual writing is done by a task that owns the
+/// write half, so sending a packet only has to queue it and never waits on the peer.
+/// A channel can be cloned, so that multiple tasks can write to the same peer.
+///
+/// Peers that don't keep up with their queue are disconnected rather than allowed to
+/// hold up the sender: see [`WriteChannel::send`].
+#[derive(Clone)]
 pub struct WriteChannel {
     addr: SocketAddr,
-    writer: BufWriter<OwnedWriteHalf>,
+    codec: Arc<dyn Codec>,
+    queue: mpsc::Sender<Outbound>,
+    writer: Arc<AbortHandle>,
+}
+
+impl fmt::Debug for WriteChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteChannel")
+            .field("addr", &self.addr)
+            .finish_non_exhaustive()
+    }
+}
+
+/// What the writer task of a [`WriteChannel`] is asked to do.
+#[derive(Debug)]
+enum Outbound {
+    /// Write a serialized packet.
+    Frame(Vec<u8>),
+    /// Compress every following packet that is larger than the threshold.
+    Compress { threshold: usize },
+    /// Shut down the write half and stop.
+    Close,
 }
 
 #[derive(Error, Debug)]
 pub enum WriteChannelError {
-    #[error("IO error: {_0}")]
-    IoError(#[from] std::io::Error),
-    #[error("Serde error: {_0}")]
-    SerdeError(#[from] rmp_serde::encode::Error),
+    #[error("Codec error: {_0}")]
+    CodecError(#[from] CodecError),
+    #[error("Outbound queue is full")]
+    Overflow,
+    #[error("Connection is closed")]
+    Closed,
 }
 
 impl WriteChannel {
-    pub fn new(write_half: OwnedWriteHalf) -> Self {
-        let addr = write_half.peer_addr().expect("expected to get peer_addr");
+    /// Creates a channel that writes to `write_half` with `codec`, with room for
+    /// `capacity` packets that haven't been written yet. `addr` is the address of the
+    /// peer, which is only used for logging.
+    pub fn new<W>(write_half: W, addr: SocketAddr, codec: Arc<dyn Codec>, capacity: usize) -> Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let (queue, rx) = mpsc::channel(capacity);
+        let writer = tokio::spawn(write_frames(addr, write_half, rx)).abort_handle();
 
         Self {
             addr,
-            writer: BufWriter::new(write_half),
+            codec,
+            queue,
+            writer: Arc::new(writer),
         }
     }
 
-    pub async fn write_next(&mut self, packet: &Packet) -> Result<(), WriteChannelError> {
+    /// Queues a packet to be written to the peer. If the peer has fallen so far
+    /// behind that its queue is full, it is disconnected and
+    /// [`WriteChannelError::Overflow`] is returned.
+    pub fn send(&self, packet: &Packet) -> Result<(), WriteChannelError> {
         let addr = self.addr;
         trace!("{addr}: sending packet {packet:?}");
 
-        let bytes = packet.to_bytes()?;
+        let bytes = self.codec.encode(packet)?;
+
+        match self.queue.try_send(Outbound::Frame(bytes)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                warn!("{addr}: outbound queue is full, disconnecting");
+                self.writer.abort();
+                Err(WriteChannelError::Overflow)
+            }
+            Err(TrySendError::Closed(_)) => Err(WriteChannelError::Closed),
+        }
+    }
+
+    /// Compresses every packet that is queued after this and is larger than
+    /// `threshold` bytes. Should only be used once the peer agreed on the
+    /// `compression` feature.
+    pub fn enable_compression(&self, threshold: usize) {
+        if self
+            .queue
+            .try_send(Outbound::Compress { threshold })
+            .is_err()
+        {
+            warn!("{}: couldn't enable compression", self.addr);
+        }
+    }
 
-        self.writer.write_u32(bytes.len() as u32).await?;
-        self.writer.write_all(&bytes).await?;
-        self.writer.flush().await?;
+    /// Shuts down the connection once the packets queued before have been written.
+    pub fn close(&self) {
+        if self.queue.try_send(Outbound::Close).is_err() {
+            self.writer.abort();
+        }
+    }
 
-        Ok(())
+    /// Completes once the writer task has stopped, whether because the channel was
+    /// closed, writing failed or the peer overflowed its queue.
+    pub async fn closed(&self) {
+        self.queue.closed().await
     }
+}
 
-    pub async fn shutdown(&mut self) -> Result<(), std::io::Error> {
-        self.writer.shutdown().await
+/// Writes queued frames to `write_half` until the channel is closed, every sender is
+/// gone or writing fails.
+async fn write_frames<W: AsyncWrite + Unpin>(
+    addr: SocketAddr,
+    write_half: W,
+    mut queue: mpsc::Receiver<Outbound>,
+) {
+    let mut writer = BufWriter::new(write_half);
+    let mut compression = None;
+
+    while let Some(outbound) = queue.recv().await {
+        let result = match outbound {
+            Outbound::Frame(bytes) => write_frame(&mut writer, &bytes, compression).await,
+            Outbound::Compress { threshold } => {
+                compression = Some(threshold);
+                continue;
+            }
+            Outbound::Close => {
+                if let Err(err) = writer.shutdown().await {
+                    trace!("{addr}: couldn't shut down connection: {err}");
+                }
+                break;
+            }
+        };
+
+        if let Err(err) = result {
+            warn!("{addr}: couldn't write packet: {err}");
+            break;
+        }
     }
 }
 
@@ -232,3 +225,80 @@ pub enum ConnRecError {
     #[error("IO error: {_0}")]
     IoError(#[from] std::io::Error),
 }
+
+/// The means to tell a client what happened to a [`Request`] it sent with an ID.
+/// Failing to send a reply is only logged, as the client may be long gone.
+///
+/// [`Request`]: Packet::Request
+#[derive(Debug)]
+pub struct RequestReply {
+    id: u32,
+    writer: WriteChannel,
+    /// Whether the client was told that the request is queued already.
+    queued: bool,
+}
+
+impl RequestReply {
+    pub fn new(id: u32, writer: WriteChannel) -> Self {
+        Self {
+            id,
+            writer,
+            queued: false,
+        }
+    }
+
+    /// Tells the client that the request will be fulfilled later on. A request that
+    /// is queued more than once, e.g. because it waited for a proxy first, is only
+    /// reported once.
+    pub fn queued(&mut self) {
+        if std::mem::replace(&mut self.queued, true) {
+            return;
+        }
+
+        self.send(Packet::RequestQueued { id: self.id });
+    }
+
+    pub fn fulfilled(self, server: String) {
+        self.send(Packet::RequestFulfilled {
+            id: self.id,
+            server,
+        });
+    }
+
+    pub fn failed(self, reason: String) {
+        self.send(Packet::RequestFailed {
+            id: self.id,
+            reason,
+        });
+    }
+
+    fn send(&self, packet: Packet) {
+        if let Err(err) = self.writer.send(&packet) {
+            warn!("couldn't reply to request {}: {err}", self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_a_queued_request_once() {
+        let (client, server) = tokio::io::duplex(1024);
+        let address = "127.0.0.1:4000".parse().unwrap();
+        let writer = WriteChannel::new(server, address, Arc::new(MessagePack), 8);
+        let mut reader = ReadChannel::new(client, 1024);
+
+        let mut reply = RequestReply::new(7, writer);
+        reply.queued();
+        reply.queued();
+        reply.fulfilled("lobby-0".to_owned());
+
+        let queued = reader.read_next().await.unwrap();
+        assert!(matches!(queued, Packet::RequestQueued { id: 7 }));
+
+        let fulfilled = reader.read_next().await.unwrap();
+        assert!(matches!(fulfilled, Packet::RequestFulfilled { id: 7, .. }));
+    }
+}
@@ -0,0 +1,140 @@
+use controller::backend::{ServerBackend, ServerInfo};
+use controller::config::Config;
+use controller::transport::Kind;
+use controller::Controller;
+use controller_client::{ClientOptions, ControllerClient, Event};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long anything in these tests may take.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs servers in memory, and remembers what happened to them.
+#[derive(Clone, Default)]
+struct MemoryBackend {
+    servers: Arc<Mutex<Servers>>,
+}
+
+#[derive(Default)]
+struct Servers {
+    /// Every server that was spawned and hasn't been stopped yet, by name.
+    running: BTreeMap<String, Kind>,
+    /// Every server that has been stopped, in the order they finished stopping.
+    stopped: Vec<String>,
+}
+
+impl MemoryBackend {
+    fn running(&self) -> Vec<String> {
+        self.servers
+            .lock()
+            .unwrap()
+            .running
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    fn stopped(&self) -> Vec<String> {
+        self.servers.lock().unwrap().stopped.clone()
+    }
+}
+
+impl ServerBackend for MemoryBackend {
+    type Error = std::io::Error;
+
+    async fn spawn(&mut self, name: String, kind: Kind, _token: String) -> Result<(), Self::Error> {
+        self.servers.lock().unwrap().running.insert(name, kind);
+        Ok(())
+    }
+
+    fn stop(
+        &mut self,
+        name: &str,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send + 'static {
+        let servers = self.servers.clone();
+        let name = name.to_owned();
+
+        async move {
+            // stopping a server takes a while, which the controller has to wait for
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            let mut servers = servers.lock().unwrap();
+            if servers.running.remove(&name).is_some() {
+                servers.stopped.push(name);
+            }
+
+            Ok(())
+        }
+    }
+
+    async fn list(&mut self) -> Result<Vec<String>, Self::Error> {
+        Ok(self.running())
+    }
+
+    async fn inspect(&mut self, name: &str) -> Result<Option<ServerInfo>, Self::Error> {
+        let servers = self.servers.lock().unwrap();
+        let info = servers.running.get(name).map(|kind| ServerInfo {
+            name: name.to_owned(),
+            kind: kind.clone(),
+            id: name.to_owned(),
+            running: true,
+        });
+
+        Ok(info)
+    }
+}
+
+#[tokio::test]
+async fn shutdown_stops_every_server() {
+    let backend = MemoryBackend::default();
+
+    let handle = Controller::builder()
+        .listen_address(([127, 0, 0, 1], 0).into())
+        .http_address(([127, 0, 0, 1], 0).into())
+        .config(Config::default())
+        .backend(backend.clone())
+        .start()
+        .await
+        .unwrap();
+
+    let options = ClientOptions::new(
+        handle.local_addr().to_string(),
+        "proxy".to_owned(),
+        Kind::Proxy,
+        "/127.0.0.1:25577".to_owned(),
+    );
+    let (_proxy, mut events) = ControllerClient::connect(options);
+
+    let event = tokio::time::timeout(TIMEOUT, events.recv()).await.unwrap();
+    assert!(matches!(event, Some(Event::Connected { .. })));
+
+    // the controller spawns a limbo server (and maybe more) on its own
+    tokio::time::timeout(TIMEOUT, async {
+        while backend.running().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the controller never spawned a server");
+
+    let spawned = backend.running();
+
+    tokio::time::timeout(TIMEOUT, handle.shutdown())
+        .await
+        .expect("shutting down never finished");
+
+    assert!(backend.running().is_empty());
+
+    let mut stopped = backend.stopped();
+    stopped.sort();
+    assert_eq!(stopped, spawned);
+
+    // the connection of the proxy is closed as well
+    tokio::time::timeout(TIMEOUT, async {
+        while !matches!(events.recv().await, Some(Event::Disconnected { .. })) {}
+    })
+    .await
+    .expect("the proxy was never disconnected");
+}
@@ -3,6 +3,7 @@ import { FaSolidComputer } from "solid-icons/fa";
 interface ComputerProps {
   name: string;
   state: "starting" | "online";
+  latency?: number;
 }
 
 export default function Computer(props: ComputerProps) {
@@ -24,6 +25,9 @@ export default function Computer(props: ComputerProps) {
         <div class="mx-auto">
           {props.state === "starting" ? "Starting..." : "Online!"}
         </div>
+        {props.latency !== undefined && (
+          <div class="mx-auto">{props.latency} ms</div>
+        )}
       </div>
     </div>
   );
@@ -4,6 +4,8 @@ import process from "node:process";
 export type StatusResp = Array<{
   name: string;
   status: "starting" | "online";
+  // Round-trip latency to the controller in milliseconds, once it's known
+  latency?: number;
 }>;
 
 const endpoint = process.env["ENDPOINT"] || "http://127.0.0.1:25580/status";
@@ -19,7 +21,11 @@ export async function GET() {
       .trim()
       .split("\n")
       .map((line) => line.split(","))
-      .map(([name, status]) => ({ name, status }));
+      .map(([name, status, latency]) => ({
+        name,
+        status,
+        latency: latency === undefined ? undefined : Number(latency),
+      }));
 
     return json(computers);
   } catch (err) {
@@ -24,8 +24,8 @@ export default function Home() {
           when={computers().length > 0}
           fallback={<div>No computers :&lt;</div>}
         >
-          {computers().map(({ name: computerName, status }) => (
-            <Computer name={computerName} state={status} />
+          {computers().map(({ name: computerName, status, latency }) => (
+            <Computer name={computerName} state={status} latency={latency} />
           ))}
         </Show>
       </Show>
@@ -4,7 +4,6 @@ import net.md_5.bungee.api.ProxyServer;
 import net.md_5.bungee.api.plugin.Plugin;
 
 import java.net.InetAddress;
-import java.net.Socket;
 import java.net.UnknownHostException;
 
 import com.sirn.transport.ManagedControllerConnection;
@@ -27,11 +26,18 @@ public class HeadControllerPlugin extends Plugin {
 			return;
 		}
 
+		// Multiple proxies may connect to the controller, so they need distinct names.
+		String proxyName = System.getenv("SERVER_NAME");
+		if (proxyName == null) {
+			proxyName = "proxy";
+		}
+
 		RejoinReconnectHandler reconnectHandler = new RejoinReconnectHandler(this.getLogger());
 		this.getProxy().setReconnectHandler(reconnectHandler);
 
-		ProxyPacketListener packetListener = new ProxyPacketListener(ProxyServer.getInstance(), reconnectHandler);
-		new ManagedControllerConnection(this.getLogger(), () -> new Socket(address, 25550), packetListener);
+		ProxyPacketListener packetListener = new ProxyPacketListener(ProxyServer.getInstance(), reconnectHandler, proxyName);
+		this.getProxy().getPluginManager().registerListener(this, packetListener);
+		new ManagedControllerConnection(this.getLogger(), ManagedControllerConnection.fromEnv(address, 25550), packetListener);
 
         System.out.println("created head controller");
     }
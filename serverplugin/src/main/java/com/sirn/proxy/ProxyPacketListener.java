@@ -13,33 +13,42 @@ import net.md_5.bungee.api.ProxyServer;
 import net.md_5.bungee.api.config.ListenerInfo;
 import net.md_5.bungee.api.config.ServerInfo;
 import net.md_5.bungee.api.connection.ProxiedPlayer;
+import net.md_5.bungee.api.event.PlayerDisconnectEvent;
+import net.md_5.bungee.api.event.PostLoginEvent;
+import net.md_5.bungee.api.plugin.Listener;
+import net.md_5.bungee.event.EventHandler;
 
-public class ProxyPacketListener extends ControllerEventListener {
+public class ProxyPacketListener extends ControllerEventListener implements Listener {
 	private final Logger logger;
 	private final ProxyServer proxyServer;
+	private final String name;
 	// For some reason, I employed levels of priority for servers.
 	// No idea why I did that in retrospect, but too lazy to change atm.
 	private final TreeMap<Integer, List<String>> serverPriorities;
 	public final RejoinReconnectHandler rejoinReconnectHandler;
 
-	public ProxyPacketListener(ProxyServer proxyServer, RejoinReconnectHandler reconnectHandler) {
+	public ProxyPacketListener(ProxyServer proxyServer, RejoinReconnectHandler reconnectHandler, String name) {
 		this.proxyServer = proxyServer;
+		this.name = name;
 		this.logger = this.proxyServer.getLogger();
 		this.serverPriorities = new TreeMap<>();
 		this.rejoinReconnectHandler = reconnectHandler;
 	}
 
 	private ControllerConnection connection;
+	// Until the controller accepted us, it only expects the packets of the handshake.
+	private volatile boolean authenticated = false;
 
 	@Override
 	public void onConnect(ControllerConnection connection) throws IOException {
 		this.connection = connection;
+		this.authenticated = false;
 
 		AuthenticationKind authenticationKind = new AuthenticationKind();
 		authenticationKind.tag = "Proxy";
 
 		AuthenticationPacket authenticationPacket = new AuthenticationPacket();
-		authenticationPacket.name = "proxy";
+		authenticationPacket.name = this.name;
 		authenticationPacket.kind = authenticationKind;
 
 		ListenerInfo listenerInfo = this.proxyServer.getConfig().getListeners().iterator().next();
@@ -50,6 +59,40 @@ public class ProxyPacketListener extends ControllerEventListener {
 		this.logger.info("Authentication packet sent");
 	}
 
+	@Override
+	public void onAuthenticationAcceptedPacket(AuthenticationAcceptedPacket packet) throws IOException {
+		this.authenticated = true;
+
+		// The controller needs to know which proxy every player is on, including
+		// the players that joined while we weren't connected.
+		for (ProxiedPlayer player : this.proxyServer.getPlayers()) {
+			this.connection.write(new PlayerJoinedPacket(player.getUniqueId().toString()));
+		}
+	}
+
+	@EventHandler
+	public void onPostLogin(PostLoginEvent event) {
+		if (!this.authenticated) return;
+
+		try {
+			this.connection.write(new PlayerJoinedPacket(event.getPlayer().getUniqueId().toString()));
+		} catch (IOException e) {
+			// It's fine to ignore any errors here, as we tell the controller about
+			// every player again when we reconnect.
+		}
+	}
+
+	@EventHandler
+	public void onPlayerDisconnect(PlayerDisconnectEvent event) {
+		if (!this.authenticated) return;
+
+		try {
+			this.connection.write(new PlayerLeftPacket(event.getPlayer().getUniqueId().toString()));
+		} catch (IOException e) {
+			// Same as above, the controller forgets about our players if we disconnect.
+		}
+	}
+
 	@Override
 	public void onDisconnect() {
 		this.logger.info("Proxy disconnected from controller!");
@@ -71,6 +114,12 @@ public class ProxyPacketListener extends ControllerEventListener {
 		);
 		proxyServer.getConfig().addServer(linkedServerInfo);
 
+		// The controller syncs every server again when we reconnect, so forget
+		// about any previous link to a server with the same name.
+		for (List<String> servers : this.serverPriorities.values()) {
+			servers.remove(packet.name);
+		}
+
 		// Add the newly linked server to the list of servers that take priority in that bucket.
 		List<String> serversInPriorityBucket = this.serverPriorities.get(packet.priority);
 		if (serversInPriorityBucket == null) {
@@ -99,6 +148,36 @@ public class ProxyPacketListener extends ControllerEventListener {
 		this.recomputeServerJoinOrder();
 	}
 
+	@Override
+	public void onSyncServersPacket(SyncServersPacket packet) {
+		this.logger.info("Syncing servers " + packet);
+
+		Set<String> syncedServers = new HashSet<>();
+		for (LinkServerPacket server : packet.servers) {
+			syncedServers.add(server.name);
+		}
+
+		// Unlink every server that the controller no longer knows about
+		for (List<String> serversInPriorityBucket : this.serverPriorities.values()) {
+			for (String name : new ArrayList<>(serversInPriorityBucket)) {
+				if (syncedServers.contains(name)) continue;
+
+				serversInPriorityBucket.remove(name);
+
+				ServerInfo serverInfo = this.proxyServer.getServerInfo(name);
+				if (serverInfo != null) {
+					this.proxyServer.getConfig().removeServer(serverInfo);
+				}
+			}
+		}
+
+		for (LinkServerPacket server : packet.servers) {
+			this.onLinkServerPacket(server);
+		}
+
+		this.recomputeServerJoinOrder();
+	}
+
 	@Override
 	public void onTransportPlayerPacket(TransportPlayerPacket packet) {
 		// The logging is ugly but after picking back up this project it helped me out, /shrug
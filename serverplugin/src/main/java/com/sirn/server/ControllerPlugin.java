@@ -10,7 +10,6 @@ import com.sirn.transport.packets.AuthenticationPayload;
 import org.bukkit.plugin.java.JavaPlugin;
 
 import java.net.InetAddress;
-import java.net.Socket;
 import java.net.UnknownHostException;
 
 public class ControllerPlugin extends JavaPlugin {
@@ -34,6 +33,13 @@ public class ControllerPlugin extends JavaPlugin {
         }
         authenticationPacket.name = serverName;
 
+        String spawnToken = System.getenv("SPAWN_TOKEN");
+        if (spawnToken == null) {
+            getLogger().severe("Could not get `SPAWN_TOKEN` from env vars.");
+            return;
+        }
+        authenticationPacket.token = spawnToken;
+
         String serverKind = System.getenv("SERVER_KIND");
         if (serverKind == null) {
             getLogger().severe("Could not get `SERVER_KIND` from env vars.");
@@ -71,8 +77,9 @@ public class ControllerPlugin extends JavaPlugin {
 
         getServer().getPluginCommand("request").setExecutor(new RequestCommand(packetListener));
         getServer().getPluginCommand("close").setExecutor(new CloseCommand(packetListener));
+        getServer().getPluginManager().registerEvents(packetListener, this);
 
-		new ManagedControllerConnection(this.getLogger(), () -> new Socket(address, 25550), packetListener);
+		new ManagedControllerConnection(this.getLogger(), ManagedControllerConnection.fromEnv(address, 25550), packetListener);
 
         System.out.println("created head controller + cmds");
     }
@@ -1,13 +1,23 @@
 package com.sirn.server;
 
 import java.io.IOException;
+import java.util.Map;
+import java.util.concurrent.ConcurrentHashMap;
+import java.util.concurrent.atomic.AtomicInteger;
 import java.util.logging.Logger;
 
 import com.sirn.transport.ControllerConnection;
 import com.sirn.transport.ControllerEventListener;
 import com.sirn.transport.packets.*;
 
-public class ServerPacketListener extends ControllerEventListener {
+import org.bukkit.Bukkit;
+import org.bukkit.command.CommandSender;
+import org.bukkit.event.EventHandler;
+import org.bukkit.event.Listener;
+import org.bukkit.event.player.PlayerJoinEvent;
+import org.bukkit.event.player.PlayerQuitEvent;
+
+public class ServerPacketListener extends ControllerEventListener implements Listener {
 	private final Logger logger;
 	private final AuthenticationPacket authenticationPacket;
 
@@ -18,16 +28,106 @@ public class ServerPacketListener extends ControllerEventListener {
 
 	public ControllerConnection connection;
 	private boolean acceptingPlayers = false;
+	// Until the controller accepted us, it only expects the packets of the handshake.
+	private volatile boolean authenticated = false;
+
+	// Whoever sent each request that the controller hasn't finished handling yet,
+	// so that they can be told what happened to it.
+	private final AtomicInteger nextRequestId = new AtomicInteger();
+	private final Map<Integer, CommandSender> pendingRequests = new ConcurrentHashMap<>();
 
 	@Override
 	public void onConnect(ControllerConnection connection) throws IOException {
 		this.connection = connection;
+		this.authenticated = false;
 		this.connection.write(this.authenticationPacket);
 		this.logger.info("Authentication packet sent");
 	}
 
+	@Override
+	public void onAuthenticationAcceptedPacket(AuthenticationAcceptedPacket packet) throws IOException {
+		this.authenticated = true;
+
+		if (this.reportsPlayerCount()) {
+			this.connection.write(new PlayerCountPacket(Bukkit.getOnlinePlayers().size()));
+		}
+	}
+
+	@EventHandler
+	public void onPlayerJoin(PlayerJoinEvent event) {
+		this.updatePlayerCount(Bukkit.getOnlinePlayers().size());
+	}
+
+	@EventHandler
+	public void onPlayerQuit(PlayerQuitEvent event) {
+		// The player that is quitting is still counted as online
+		this.updatePlayerCount(Bukkit.getOnlinePlayers().size() - 1);
+	}
+
+	private boolean reportsPlayerCount() {
+		String tag = this.authenticationPacket.kind.tag;
+		return tag.equals("Lobby") || tag.equals("Minigame");
+	}
+
+	private void updatePlayerCount(int players) {
+		// Only lobby and minigame servers tell the controller about their player count
+		if (!this.authenticated || !this.reportsPlayerCount()) return;
+
+		try {
+			this.connection.write(new PlayerCountPacket(players));
+		} catch (IOException e) {
+			// It's fine to ignore any errors here, as we send our player count
+			// again when we reconnect.
+		}
+	}
+
+	/**
+	 * Sends a request to the controller, and tells the requester what happens to it.
+	 */
+	public void request(RequestPacket request, CommandSender requester) throws IOException {
+		request.id = this.nextRequestId.incrementAndGet();
+		this.pendingRequests.put(request.id, requester);
+
+		try {
+			this.connection.write(request);
+		} catch (IOException e) {
+			this.pendingRequests.remove(request.id);
+			throw e;
+		}
+	}
+
+	@Override
+	public void onRequestQueuedPacket(RequestQueuedPacket packet) {
+		CommandSender requester = this.pendingRequests.get(packet.id);
+		if (requester == null) return;
+
+		requester.sendMessage("You're in the queue, please wait a moment...");
+	}
+
+	@Override
+	public void onRequestFulfilledPacket(RequestFulfilledPacket packet) {
+		CommandSender requester = this.pendingRequests.remove(packet.id);
+		if (requester == null) return;
+
+		requester.sendMessage("Sending you to " + packet.server + "!");
+	}
+
+	@Override
+	public void onRequestFailedPacket(RequestFailedPacket packet) {
+		CommandSender requester = this.pendingRequests.remove(packet.id);
+		if (requester == null) return;
+
+		requester.sendMessage("Your request failed: " + packet.reason);
+	}
+
 	@Override
 	public void onDisconnect() {
+		// The controller forgets about our requests when we disconnect
+		for (CommandSender requester : this.pendingRequests.values()) {
+			requester.sendMessage("Lost connection to the controller, please try again");
+		}
+		this.pendingRequests.clear();
+
 		this.logger.info("Server disconnected from controller!");
 		this.logger.info("TODO: Implement more robust logic to repair things upon disconnecting");
 	}
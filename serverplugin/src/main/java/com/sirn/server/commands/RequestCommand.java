@@ -39,8 +39,7 @@ public class RequestCommand implements CommandExecutor {
 			// destroyed by the try-with-resources statement.
 			//
 			// Ignoring this problem for the time being :-)
-	        this.connection.connection.write(request);
-	        sender.sendMessage("you will be sent to a minigame server shortly (if you are not, try again)");
+	        this.connection.request(request, sender);
 		} catch (IOException e) {
 			sender.sendMessage("uh oh, big problem atm");
 		}
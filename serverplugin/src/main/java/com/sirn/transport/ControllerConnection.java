@@ -2,6 +2,7 @@ package com.sirn.transport;
 
 import com.fasterxml.jackson.core.JsonProcessingException;
 import com.fasterxml.jackson.databind.ObjectMapper;
+import com.github.luben.zstd.Zstd;
 import org.msgpack.jackson.dataformat.MessagePackFactory;
 
 import java.io.*;
@@ -9,17 +10,35 @@ import java.net.Socket;
 import java.util.logging.Logger;
 
 import com.sirn.transport.packets.AuthenticationPacket;
+import com.sirn.transport.packets.ChallengeResponsePacket;
+import com.sirn.transport.packets.HeartbeatAckPacket;
 import com.sirn.transport.packets.Packet;
+import com.sirn.transport.packets.PlayerCountPacket;
+import com.sirn.transport.packets.PlayerJoinedPacket;
+import com.sirn.transport.packets.PlayerLeftPacket;
 import com.sirn.transport.packets.PongPacket;
 import com.sirn.transport.packets.RequestPacket;
 import com.sirn.transport.packets.UpdateActivePacket;
 
 public class ControllerConnection implements Closeable {
 	private static final ObjectMapper objectMapper = new ObjectMapper(new MessagePackFactory());
+
+	/**
+	 * Set in the frame header if the frame is compressed with zstd. Must be kept in
+	 * sync with `COMPRESSED_FLAG` in `/controller/client-sdk/src/frame.rs`.
+	 */
+	private static final int COMPRESSED_FLAG = 1 << 31;
+
+	/**
+	 * Packets larger than this many bytes are compressed, once compression is enabled.
+	 */
+	private static final int COMPRESSION_THRESHOLD = 1024;
+
 	private final Logger logger;
 	private final Socket socket;
     private final DataInputStream reader;
     private final DataOutputStream writer;
+	private volatile boolean compression = false;
 
 	public ControllerConnection(Logger logger, Socket socket) throws IOException {
 		this.logger = logger;
@@ -29,10 +48,18 @@ public class ControllerConnection implements Closeable {
 	}
 
 	public Packet read() throws IOException {
-		int length = reader.readInt();
-		byte[] bytes = new byte[length];
+		int header = reader.readInt();
+		byte[] bytes = new byte[header & ~COMPRESSED_FLAG];
 		reader.readFully(bytes);
 
+		if ((header & COMPRESSED_FLAG) != 0) {
+			long size = Zstd.decompressedSize(bytes);
+			if (size <= 0 || size > Integer.MAX_VALUE) {
+				throw new IOException("Compressed frame has an invalid size: " + size);
+			}
+			bytes = Zstd.decompress(bytes, (int) size);
+		}
+
 		Packet packet;
 		packet = objectMapper.readValue(bytes, Packet.class);
 		this.logger.info("Received packet: " + packet);
@@ -40,6 +67,14 @@ public class ControllerConnection implements Closeable {
 		return packet;
 	}
 
+	/**
+	 * Compresses large packets from now on. Should only be called once the controller
+	 * agreed on the `compression` feature.
+	 */
+	public void enableCompression() {
+		this.compression = true;
+	}
+
 	// Type-safe methods for only the packets we're allowed to send
 
 	public void write(AuthenticationPacket packet) throws IOException {
@@ -48,6 +83,12 @@ public class ControllerConnection implements Closeable {
 		this.write(wrapperPacket);
 	}
 
+	public void write(ChallengeResponsePacket packet) throws IOException {
+		Packet wrapperPacket = new Packet();
+		wrapperPacket.challengeResponsePacket = packet;
+		this.write(wrapperPacket);
+	}
+
 	public void write(RequestPacket packet) throws IOException {
 		Packet wrapperPacket = new Packet();
 		wrapperPacket.requestPacket = packet;
@@ -66,7 +107,31 @@ public class ControllerConnection implements Closeable {
 		this.write(wrapperPacket);
 	}
 
-	private void write(Packet packet) throws IOException {
+	public void write(PlayerJoinedPacket packet) throws IOException {
+		Packet wrapperPacket = new Packet();
+		wrapperPacket.playerJoinedPacket = packet;
+		this.write(wrapperPacket);
+	}
+
+	public void write(PlayerLeftPacket packet) throws IOException {
+		Packet wrapperPacket = new Packet();
+		wrapperPacket.playerLeftPacket = packet;
+		this.write(wrapperPacket);
+	}
+
+	public void write(HeartbeatAckPacket packet) throws IOException {
+		Packet wrapperPacket = new Packet();
+		wrapperPacket.heartbeatAckPacket = packet;
+		this.write(wrapperPacket);
+	}
+
+	public void write(PlayerCountPacket packet) throws IOException {
+		Packet wrapperPacket = new Packet();
+		wrapperPacket.playerCountPacket = packet;
+		this.write(wrapperPacket);
+	}
+
+	private synchronized void write(Packet packet) throws IOException {
 		this.logger.info("Writing packet: " + packet);
 
         byte[] payload;
@@ -77,6 +142,17 @@ public class ControllerConnection implements Closeable {
             return;
         }
 
+        if (this.compression && payload.length > COMPRESSION_THRESHOLD) {
+            byte[] compressed = Zstd.compress(payload);
+
+            // not every packet gets smaller
+            if (compressed.length < payload.length) {
+                this.writer.writeInt(compressed.length | COMPRESSED_FLAG);
+                this.writer.write(compressed);
+                return;
+            }
+        }
+
         this.writer.writeInt(payload.length);
         this.writer.write(payload);
 	}
@@ -8,9 +8,14 @@ public abstract class ControllerEventListener {
 	public abstract void onConnect(ControllerConnection connection) throws IOException;
 	public void onDisconnect() {}
 
+	public void onAuthenticationAcceptedPacket(AuthenticationAcceptedPacket packet) throws IOException {}
 	public void onLinkServerPacket(LinkServerPacket packet) throws IOException {}
 	public void onUnlinkServerPacket(UnlinkServerPacket packet) throws IOException {}
+	public void onSyncServersPacket(SyncServersPacket packet) throws IOException {}
 	public void onTransportPlayerPacket(TransportPlayerPacket packet) throws IOException {}
 	public void onRequestPacket(RequestPacket packet) throws IOException {}
+	public void onRequestQueuedPacket(RequestQueuedPacket packet) throws IOException {}
+	public void onRequestFulfilledPacket(RequestFulfilledPacket packet) throws IOException {}
+	public void onRequestFailedPacket(RequestFailedPacket packet) throws IOException {}
 	public void onPingPacket(PingPacket packet) throws IOException {}
 }
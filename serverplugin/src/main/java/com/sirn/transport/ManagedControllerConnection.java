@@ -1,9 +1,20 @@
 package com.sirn.transport;
 
 import java.io.IOException;
+import java.net.InetAddress;
 import java.net.Socket;
+import java.nio.charset.StandardCharsets;
+import java.security.GeneralSecurityException;
 import java.util.logging.Logger;
 
+import javax.crypto.Mac;
+import javax.crypto.spec.SecretKeySpec;
+import javax.net.ssl.SSLParameters;
+import javax.net.ssl.SSLSocket;
+import javax.net.ssl.SSLSocketFactory;
+
+import com.sirn.transport.packets.ChallengeResponsePacket;
+import com.sirn.transport.packets.HeartbeatAckPacket;
 import com.sirn.transport.packets.Packet;
 
 public class ManagedControllerConnection {
@@ -11,6 +22,30 @@ public class ManagedControllerConnection {
 		public Socket create() throws IOException;
 	}
 
+	/**
+	 * Connects to the controller at the given address, using TLS if the `CONTROLLER_TLS`
+	 * env var is `true`. The certificates to trust, and the client certificate to present
+	 * if the controller requires one, are configured with the standard `javax.net.ssl.*`
+	 * system properties.
+	 */
+	public static SocketFactory fromEnv(InetAddress address, int port) {
+		if (!"true".equals(System.getenv("CONTROLLER_TLS"))) {
+			return () -> new Socket(address, port);
+		}
+
+		return () -> {
+			SSLSocket socket = (SSLSocket) SSLSocketFactory.getDefault().createSocket(address, port);
+
+			// Make sure we're talking to the controller, and not whoever is in between
+			SSLParameters parameters = socket.getSSLParameters();
+			parameters.setEndpointIdentificationAlgorithm("HTTPS");
+			socket.setSSLParameters(parameters);
+
+			socket.startHandshake();
+			return socket;
+		};
+	}
+
 	private final Logger logger;
 	private final SocketFactory factory;
 	private final ControllerEventListener listener;
@@ -57,16 +92,47 @@ public class ManagedControllerConnection {
 					// These cases intentionally unhandled as they are C -> S:
 					//
 					// - authenticationPacket
+					// - challengeResponsePacket
+					// - heartbeatAckPacket
 					// - requestPacket
 					// - pongPacket
 					// - updateActivePacket
+					// - playerJoinedPacket
+					// - playerLeftPacket
+					// - playerCountPacket
 
-					if (packet.linkServerPacket != null) {
+					if (packet.challengePacket != null) {
+						String signature = sign(packet.challengePacket.nonce);
+						connection.write(new ChallengeResponsePacket(signature));
+					} else if (packet.heartbeatPacket != null) {
+						connection.write(new HeartbeatAckPacket(packet.heartbeatPacket.sent_at));
+					} else if (packet.authenticationAcceptedPacket != null) {
+						if (packet.authenticationAcceptedPacket.features.contains("compression")) {
+							connection.enableCompression();
+						}
+						listener.onAuthenticationAcceptedPacket(packet.authenticationAcceptedPacket);
+					} else if (packet.authenticationRejectedPacket != null) {
+						// There's no point in talking to a controller that doesn't understand us,
+						// or that doesn't trust us. Spawn tokens can only be used once, so a
+						// spawned server that reconnects is always rejected.
+						this.listener.onDisconnect();
+						this.logger.severe("Controller rejected authentication, not reconnecting: "
+								+ packet.authenticationRejectedPacket.reason);
+						return;
+					} else if (packet.linkServerPacket != null) {
 						listener.onLinkServerPacket(packet.linkServerPacket);
 					} else if (packet.unlinkServerPacket != null) {
 						listener.onUnlinkServerPacket(packet.unlinkServerPacket);
+					} else if (packet.syncServersPacket != null) {
+						listener.onSyncServersPacket(packet.syncServersPacket);
 					} else if (packet.transportPlayerPacket != null) {
 						listener.onTransportPlayerPacket(packet.transportPlayerPacket);
+					} else if (packet.requestQueuedPacket != null) {
+						listener.onRequestQueuedPacket(packet.requestQueuedPacket);
+					} else if (packet.requestFulfilledPacket != null) {
+						listener.onRequestFulfilledPacket(packet.requestFulfilledPacket);
+					} else if (packet.requestFailedPacket != null) {
+						listener.onRequestFailedPacket(packet.requestFailedPacket);
 					} else if (packet.pingPacket != null) {
 						listener.onPingPacket(packet.pingPacket);
 					} else {
@@ -81,4 +147,30 @@ public class ManagedControllerConnection {
 			}
 		}
 	}
+
+	/**
+	 * Proves to the controller that we know the shared secret, by signing the nonce
+	 * it sent us with HMAC-SHA256.
+	 */
+	private static String sign(String nonce) throws IOException {
+		String secret = System.getenv("CONTROLLER_SECRET");
+		if (secret == null) {
+			throw new IOException("Controller wants us to authenticate, but `CONTROLLER_SECRET` is not set");
+		}
+
+		byte[] signature;
+		try {
+			Mac mac = Mac.getInstance("HmacSHA256");
+			mac.init(new SecretKeySpec(secret.getBytes(StandardCharsets.UTF_8), "HmacSHA256"));
+			signature = mac.doFinal(nonce.getBytes(StandardCharsets.UTF_8));
+		} catch (GeneralSecurityException e) {
+			throw new IOException("Couldn't sign the challenge", e);
+		}
+
+		StringBuilder hex = new StringBuilder(signature.length * 2);
+		for (byte b : signature) {
+			hex.append(String.format("%02x", b));
+		}
+		return hex.toString();
+	}
 }
@@ -0,0 +1,16 @@
+package com.sirn.transport.packets;
+
+import java.util.List;
+
+public class AuthenticationAcceptedPacket {
+    public int version;
+    public List<String> features;
+
+    @Override
+    public String toString() {
+        return "AuthenticationAcceptedPacket{" +
+                "version=" + version +
+                ", features=" + features +
+                '}';
+    }
+}
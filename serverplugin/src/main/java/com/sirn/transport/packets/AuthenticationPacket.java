@@ -1,9 +1,26 @@
 package com.sirn.transport.packets;
 
+import java.util.Arrays;
+import java.util.List;
+
 public class AuthenticationPacket {
+    /**
+     * The protocol version this plugin speaks. Must be kept in sync with
+     * `PROTOCOL_VERSION` in `/controller/client-sdk/src/protocol.rs`.
+     */
+    public static final int PROTOCOL_VERSION = 3;
+
+    /**
+     * The optional protocol features this plugin supports.
+     */
+    public static final List<String> FEATURES = Arrays.asList("sync-servers", "compression");
+
     public String name;
     public AuthenticationKind kind;
     public String ip;
+    public int version = PROTOCOL_VERSION;
+    public List<String> features = FEATURES;
+    public String token;
 
     @Override
     public String toString() {
@@ -11,6 +28,9 @@ public class AuthenticationPacket {
                 "name='" + name + '\'' +
                 ", kind=" + kind +
                 ", ip='" + ip + '\'' +
+                ", version=" + version +
+                ", features=" + features +
+                ", token=" + (token == null ? "null" : "<redacted>") +
                 '}';
     }
 }
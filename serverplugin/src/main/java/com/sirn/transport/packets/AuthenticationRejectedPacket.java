@@ -0,0 +1,14 @@
+package com.sirn.transport.packets;
+
+public class AuthenticationRejectedPacket {
+    public int version;
+    public String reason;
+
+    @Override
+    public String toString() {
+        return "AuthenticationRejectedPacket{" +
+                "version=" + version +
+                ", reason='" + reason + '\'' +
+                '}';
+    }
+}
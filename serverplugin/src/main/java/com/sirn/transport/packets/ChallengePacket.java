@@ -0,0 +1,12 @@
+package com.sirn.transport.packets;
+
+public class ChallengePacket {
+    public String nonce;
+
+    @Override
+    public String toString() {
+        return "ChallengePacket{" +
+                "nonce='" + nonce + '\'' +
+                '}';
+    }
+}
@@ -0,0 +1,16 @@
+package com.sirn.transport.packets;
+
+public class ChallengeResponsePacket {
+    public String signature;
+
+    public ChallengeResponsePacket(String signature) {
+        this.signature = signature;
+    }
+
+    @Override
+    public String toString() {
+        return "ChallengeResponsePacket{" +
+                "signature=<redacted>" +
+                '}';
+    }
+}
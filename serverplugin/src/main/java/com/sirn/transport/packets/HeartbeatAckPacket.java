@@ -0,0 +1,16 @@
+package com.sirn.transport.packets;
+
+public class HeartbeatAckPacket {
+    public long sent_at;
+
+    public HeartbeatAckPacket(long sent_at) {
+        this.sent_at = sent_at;
+    }
+
+    @Override
+    public String toString() {
+        return "HeartbeatAckPacket{" +
+                "sent_at=" + sent_at +
+                '}';
+    }
+}
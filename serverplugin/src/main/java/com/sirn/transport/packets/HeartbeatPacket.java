@@ -0,0 +1,12 @@
+package com.sirn.transport.packets;
+
+public class HeartbeatPacket {
+    public long sent_at;
+
+    @Override
+    public String toString() {
+        return "HeartbeatPacket{" +
+                "sent_at=" + sent_at +
+                '}';
+    }
+}
@@ -6,25 +6,49 @@ import com.fasterxml.jackson.annotation.JsonProperty;
 /**
  * Poor man's discriminated union. Only one of the public fields is guaranteed to be non-null.
  *
- * For documentation on packets, see `/controller/src/transport.rs`.
+ * For documentation on packets, see `/controller/client-sdk/src/protocol.rs`.
  */
 @JsonInclude(JsonInclude.Include.NON_NULL)
 public class Packet {
     @JsonProperty(value = "Authentication")
     public AuthenticationPacket authenticationPacket;
 
+    @JsonProperty(value = "Challenge")
+    public ChallengePacket challengePacket;
+
+    @JsonProperty(value = "ChallengeResponse")
+    public ChallengeResponsePacket challengeResponsePacket;
+
+    @JsonProperty(value = "AuthenticationAccepted")
+    public AuthenticationAcceptedPacket authenticationAcceptedPacket;
+
+    @JsonProperty(value = "AuthenticationRejected")
+    public AuthenticationRejectedPacket authenticationRejectedPacket;
+
     @JsonProperty(value = "LinkServer")
     public LinkServerPacket linkServerPacket;
 
     @JsonProperty(value = "UnlinkServer")
     public UnlinkServerPacket unlinkServerPacket;
 
+    @JsonProperty(value = "SyncServers")
+    public SyncServersPacket syncServersPacket;
+
     @JsonProperty(value = "TransportPlayer")
     public TransportPlayerPacket transportPlayerPacket;
 
     @JsonProperty(value = "Request")
     public RequestPacket requestPacket;
 
+    @JsonProperty(value = "RequestQueued")
+    public RequestQueuedPacket requestQueuedPacket;
+
+    @JsonProperty(value = "RequestFulfilled")
+    public RequestFulfilledPacket requestFulfilledPacket;
+
+    @JsonProperty(value = "RequestFailed")
+    public RequestFailedPacket requestFailedPacket;
+
     @JsonProperty(value = "Ping")
     public PingPacket pingPacket;
 
@@ -34,6 +58,21 @@ public class Packet {
     @JsonProperty(value = "UpdateActive")
     public UpdateActivePacket updateActivePacket;
 
+    @JsonProperty(value = "PlayerJoined")
+    public PlayerJoinedPacket playerJoinedPacket;
+
+    @JsonProperty(value = "PlayerLeft")
+    public PlayerLeftPacket playerLeftPacket;
+
+    @JsonProperty(value = "Heartbeat")
+    public HeartbeatPacket heartbeatPacket;
+
+    @JsonProperty(value = "HeartbeatAck")
+    public HeartbeatAckPacket heartbeatAckPacket;
+
+    @JsonProperty(value = "PlayerCount")
+    public PlayerCountPacket playerCountPacket;
+
     public Packet() {}
 
     public Packet(PongPacket pongPacket) {
@@ -52,13 +91,26 @@ public class Packet {
     public String toString() {
         return "Packet{" +
                 "authenticationPacket=" + authenticationPacket +
+                ", challengePacket=" + challengePacket +
+                ", challengeResponsePacket=" + challengeResponsePacket +
+                ", authenticationAcceptedPacket=" + authenticationAcceptedPacket +
+                ", authenticationRejectedPacket=" + authenticationRejectedPacket +
                 ", linkServerPacket=" + linkServerPacket +
                 ", unlinkServerPacket=" + unlinkServerPacket +
+                ", syncServersPacket=" + syncServersPacket +
                 ", transportPlayerPacket=" + transportPlayerPacket +
                 ", requestPacket=" + requestPacket +
+                ", requestQueuedPacket=" + requestQueuedPacket +
+                ", requestFulfilledPacket=" + requestFulfilledPacket +
+                ", requestFailedPacket=" + requestFailedPacket +
                 ", pingPacket=" + pingPacket +
                 ", pongPacket=" + pongPacket +
                 ", updateActivePacket=" + updateActivePacket +
+                ", playerJoinedPacket=" + playerJoinedPacket +
+                ", playerLeftPacket=" + playerLeftPacket +
+                ", heartbeatPacket=" + heartbeatPacket +
+                ", heartbeatAckPacket=" + heartbeatAckPacket +
+                ", playerCountPacket=" + playerCountPacket +
                 '}';
     }
 }
@@ -0,0 +1,16 @@
+package com.sirn.transport.packets;
+
+public class PlayerCountPacket {
+    public int players;
+
+    public PlayerCountPacket(int players) {
+        this.players = players;
+    }
+
+    @Override
+    public String toString() {
+        return "PlayerCountPacket{" +
+                "players=" + players +
+                '}';
+    }
+}
@@ -0,0 +1,16 @@
+package com.sirn.transport.packets;
+
+public class PlayerJoinedPacket {
+    public String player;
+
+    public PlayerJoinedPacket(String player) {
+        this.player = player;
+    }
+
+    @Override
+    public String toString() {
+        return "PlayerJoinedPacket{" +
+                "player='" + player + '\'' +
+                '}';
+    }
+}
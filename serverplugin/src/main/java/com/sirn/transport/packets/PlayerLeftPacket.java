@@ -0,0 +1,16 @@
+package com.sirn.transport.packets;
+
+public class PlayerLeftPacket {
+    public String player;
+
+    public PlayerLeftPacket(String player) {
+        this.player = player;
+    }
+
+    @Override
+    public String toString() {
+        return "PlayerLeftPacket{" +
+                "player='" + player + '\'' +
+                '}';
+    }
+}
@@ -0,0 +1,14 @@
+package com.sirn.transport.packets;
+
+public class RequestFailedPacket {
+    public int id;
+    public String reason;
+
+    @Override
+    public String toString() {
+        return "RequestFailedPacket{" +
+                "id=" + id +
+                ", reason='" + reason + '\'' +
+                '}';
+    }
+}
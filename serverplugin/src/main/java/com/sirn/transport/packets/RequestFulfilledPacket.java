@@ -0,0 +1,14 @@
+package com.sirn.transport.packets;
+
+public class RequestFulfilledPacket {
+    public int id;
+    public String server;
+
+    @Override
+    public String toString() {
+        return "RequestFulfilledPacket{" +
+                "id=" + id +
+                ", server='" + server + '\'' +
+                '}';
+    }
+}
@@ -6,6 +6,7 @@ import com.fasterxml.jackson.annotation.JsonInclude;
 public class RequestPacket {
     public AuthenticationKind kind;
     public String player;
+    public Integer id;
 
     public RequestPacket(AuthenticationKind kind, String player) {
         this.kind = kind;
@@ -17,6 +18,7 @@ public class RequestPacket {
         return "RequestPacket{" +
                 "kind=" + kind +
                 ", player='" + player + '\'' +
+                ", id=" + id +
                 '}';
     }
 }
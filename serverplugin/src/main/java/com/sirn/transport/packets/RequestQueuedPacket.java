@@ -0,0 +1,12 @@
+package com.sirn.transport.packets;
+
+public class RequestQueuedPacket {
+    public int id;
+
+    @Override
+    public String toString() {
+        return "RequestQueuedPacket{" +
+                "id=" + id +
+                '}';
+    }
+}
@@ -0,0 +1,14 @@
+package com.sirn.transport.packets;
+
+import java.util.List;
+
+public class SyncServersPacket {
+    public List<LinkServerPacket> servers;
+
+    @Override
+    public String toString() {
+        return "SyncServersPacket{" +
+                "servers=" + servers +
+                '}';
+    }
+}